@@ -0,0 +1,460 @@
+use crate::config::Config;
+use crate::export::{Format, Layout};
+use crate::health::{Backend, Health};
+use crate::history::{History, Range};
+use crate::max6675::{Channel, Chip, Temperatures};
+use crate::measurement::{unix_seconds, Measurement, Measurements, Samples};
+use crate::recorder::{self, Recorder, SessionInfo};
+use crate::runs::{self, Run, Runs, Snapshot};
+use crate::scope::Channel as ScopeChannel;
+use crate::scpi;
+use crate::waveform::{self, Capture, Waveform};
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Shutdown, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// All the current readings of the station
+#[derive(Serialize)]
+pub struct Readings {
+    /// Unix time when the response was created
+    timestamp: f64,
+    sensors: Vec<SensorReading>,
+    ambient_temperature: Value,
+    /// Measurements of every instrument by the instrument and the measurement name,
+    /// the names are the prefix and the last part of the series
+    instruments: BTreeMap<String, BTreeMap<String, Value>>,
+    power: Value,
+}
+
+/// Current reading of one thermocouple channel, temperatures are in ˚C
+#[derive(Serialize)]
+pub struct SensorReading {
+    name: String,
+    chip: Chip,
+    location: Option<String>,
+    dut: Option<String>,
+    raw: Option<f64>,
+    calibrated: Option<f64>,
+    filtered: Option<f64>,
+    cold_junction: Option<f64>,
+    offset: Option<f64>,
+    up: bool,
+    /// The sensor was not read for too long, the temperatures are omitted
+    stale: bool,
+    /// Unix time of the last successful read
+    timestamp: Option<f64>,
+    /// Number of failed reads per fault kind
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl SensorReading {
+    fn new(temperatures: &Temperatures, channel: &Channel) -> Self {
+        let name = &channel.name;
+        let raw = temperatures.current_raw(name);
+        let offset = temperatures.calibration.get(name).copied();
+        let status = temperatures.status.get(name);
+        let last_read = status.and_then(|status| status.last_read);
+        let stale = temperatures.is_stale(name);
+        // The filter holds its initial value until the sensor is read for the first time
+        let filtered = temperatures
+            .filtered
+            .get(name)
+            .filter(|_| last_read.is_some() && !stale)
+            .map(|filter| filter.value());
+
+        Self {
+            name: name.clone(),
+            chip: channel.chip,
+            location: channel.location.clone(),
+            dut: channel.dut.clone(),
+            raw,
+            calibrated: raw.zip(offset).map(|(raw, offset)| raw + offset),
+            filtered: filtered
+                .zip(offset)
+                .map(|(filtered, offset)| filtered + offset),
+            cold_junction: temperatures.current_cold_junction(name),
+            offset,
+            up: status.is_some_and(|status| status.up),
+            stale,
+            timestamp: last_read.map(unix_seconds),
+            errors: status
+                .map(|status| status.errors.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Current reading of a single value source
+#[derive(Serialize)]
+pub struct Value {
+    value: Option<f64>,
+    unit: String,
+    up: bool,
+    /// The value was not read for too long, it is omitted
+    stale: bool,
+    /// Unix time of the last successful read
+    timestamp: Option<f64>,
+}
+
+impl Value {
+    fn new(measurement: &Arc<Mutex<Measurement>>, unit: &str) -> Self {
+        let measurement = *measurement
+            .lock()
+            .expect("BUG: Failed to acquire measurement lock");
+        Self {
+            value: measurement.current(),
+            unit: unit.to_owned(),
+            up: measurement.up,
+            stale: measurement.is_stale(),
+            timestamp: measurement.last_read.map(unix_seconds),
+        }
+    }
+}
+
+#[get("/readings")]
+pub fn readings(measurements: &State<Measurements>, config: &State<Config>) -> Json<Readings> {
+    let sensors = {
+        let temperatures = measurements
+            .temperatures
+            .lock()
+            .expect("BUG: Failed to acquire temperatures lock");
+        temperatures
+            .channels
+            .iter()
+            .map(|channel| SensorReading::new(&temperatures, channel))
+            .collect()
+    };
+
+    Json(Readings {
+        timestamp: unix_seconds(SystemTime::now()),
+        sensors,
+        ambient_temperature: Value::new(&measurements.ambient_temperature, "˚C"),
+        instruments: config
+            .instruments
+            .iter()
+            .map(|instrument| {
+                let values = instrument
+                    .measurements
+                    .iter()
+                    .filter_map(|query| {
+                        let measurement =
+                            measurements.instruments[&instrument.name].get(&query.name)?;
+                        Some((query.name.clone(), Value::new(measurement, &query.unit)))
+                    })
+                    .collect();
+                (instrument.name.clone(), values)
+            })
+            .collect(),
+        power: Value::new(&measurements.power, "W"),
+    })
+}
+
+#[get("/sensors/<name>")]
+pub fn sensor(name: &str, measurements: &State<Measurements>) -> Option<Json<SensorReading>> {
+    let temperatures = measurements
+        .temperatures
+        .lock()
+        .expect("BUG: Failed to acquire temperatures lock");
+    let channel = temperatures
+        .channels
+        .iter()
+        .find(|channel| channel.name == name)?;
+    Some(Json(SensorReading::new(&temperatures, channel)))
+}
+
+/// Server-Sent Events with every new sample, optionally only the series starting with `prefix`
+#[get("/stream?<prefix>")]
+pub fn stream(
+    prefix: Option<String>,
+    samples: &State<Samples>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = samples.subscribe();
+    EventStream! {
+        loop {
+            let sample = select! {
+                sample = receiver.recv() => match sample {
+                    Ok(sample) => sample,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Stream client is too slow, {skipped} samples skipped");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            if prefix.as_ref().is_some_and(|prefix| !sample.series.starts_with(prefix.as_str())) {
+                continue;
+            }
+            yield Event::json(&sample);
+        }
+    }
+}
+
+/// Names of all the recorded series
+#[get("/series")]
+pub fn series(history: &State<History>) -> Json<Vec<String>> {
+    Json(history.names())
+}
+
+/// Time window in unix seconds, negative times are relative to now,
+/// by default the window covers the whole history
+fn time_window(from: Option<f64>, to: Option<f64>) -> (f64, f64) {
+    let now = unix_seconds(SystemTime::now());
+    let absolute = |time: f64| match time < 0.0 {
+        true => now + time,
+        false => time,
+    };
+    (from.map_or(0.0, absolute), to.map_or(now, absolute))
+}
+
+/// Points of the series in the window, all the series if none is given,
+/// the series without any sample yet have no points
+fn query_history(
+    history: &History,
+    series: Vec<String>,
+    (from, to): (f64, f64),
+    step: Option<f64>,
+) -> Vec<Range> {
+    let series = match series.is_empty() {
+        true => history.names(),
+        false => series,
+    };
+    series
+        .into_iter()
+        .map(|name| {
+            history.query(&name, from, to, step).unwrap_or(Range {
+                series: name,
+                unit: "",
+                points: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// Recorded points of the series, all of them if none is given,
+/// `from` and `to` are unix seconds, negative ones are relative to now
+#[get("/history?<series>&<from>&<to>&<step>")]
+pub fn history(
+    series: Vec<String>,
+    from: Option<f64>,
+    to: Option<f64>,
+    step: Option<f64>,
+    history: &State<History>,
+) -> Json<Vec<Range>> {
+    Json(query_history(history, series, time_window(from, to), step))
+}
+
+/// Parameters of `/export`
+#[derive(FromForm)]
+pub struct ExportQuery {
+    format: Option<Format>,
+    layout: Option<Layout>,
+    series: Vec<String>,
+    from: Option<f64>,
+    to: Option<f64>,
+    step: Option<f64>,
+    run: Option<u64>,
+}
+
+/// Recorded points as CSV or JSON, CSV in the wide layout by default,
+/// the window is given by `from` and `to` like in `/history`, or by the test `run`,
+/// wide layout averages the values over `step`, 1 second by default, to align the columns
+#[get("/export?<query..>")]
+pub fn export(
+    query: ExportQuery,
+    history: &State<History>,
+    runs: &State<Runs>,
+) -> Option<(ContentType, String)> {
+    let ExportQuery {
+        format,
+        layout,
+        series,
+        from,
+        to,
+        step,
+        run,
+    } = query;
+    let window = match run {
+        Some(id) => {
+            let run = runs.get(id)?;
+            let stopped = run.stopped.unwrap_or(unix_seconds(SystemTime::now()));
+            (run.started, stopped)
+        }
+        None => time_window(from, to),
+    };
+    let format = format.unwrap_or(Format::Csv);
+    let layout = layout.unwrap_or(Layout::Wide);
+    let step = match layout {
+        Layout::Wide => step.or(Some(1.0)),
+        Layout::Long => step,
+    };
+
+    let ranges = query_history(history, series, window, step);
+    let content_type = match format {
+        Format::Csv => ContentType::CSV,
+        Format::Json => ContentType::JSON,
+    };
+    Some((content_type, crate::export::render(&ranges, format, layout)))
+}
+
+#[derive(Deserialize)]
+pub struct RecordingRequest {
+    name: String,
+}
+
+fn recorder_error(err: recorder::Error) -> (Status, String) {
+    let status = match err {
+        recorder::Error::AlreadyRecording(_) | recorder::Error::Exists(_) => Status::Conflict,
+        recorder::Error::InvalidName(_) => Status::BadRequest,
+        recorder::Error::NotRecording => Status::NotFound,
+        recorder::Error::Io(_) => Status::InternalServerError,
+    };
+    (status, err.to_string())
+}
+
+/// Session being recorded, `null` if there is none
+#[get("/recording")]
+pub fn recording(recorder: &State<Recorder>) -> Json<Option<SessionInfo>> {
+    Json(recorder.status())
+}
+
+#[post("/recording", data = "<request>")]
+pub fn start_recording(
+    request: Json<RecordingRequest>,
+    recorder: &State<Recorder>,
+) -> Result<Json<SessionInfo>, (Status, String)> {
+    recorder
+        .start(&request.name)
+        .map(Json)
+        .map_err(recorder_error)
+}
+
+#[post("/recording/stop")]
+pub fn stop_recording(recorder: &State<Recorder>) -> Result<Json<SessionInfo>, (Status, String)> {
+    recorder.stop().map(Json).map_err(recorder_error)
+}
+
+fn runs_error(err: runs::Error) -> (Status, String) {
+    let status = match err {
+        runs::Error::Active(_) | runs::Error::Stopped(_) => Status::Conflict,
+        runs::Error::NotFound(_) => Status::NotFound,
+        runs::Error::Io(_) | runs::Error::Parse(_) => Status::InternalServerError,
+    };
+    (status, err.to_string())
+}
+
+#[get("/runs")]
+pub fn list_runs(runs: &State<Runs>) -> Json<Vec<Run>> {
+    Json(runs.list())
+}
+
+#[get("/runs/<id>")]
+pub fn get_run(id: u64, runs: &State<Runs>) -> Option<Json<Run>> {
+    runs.get(id).map(Json)
+}
+
+/// Starts the run with the snapshot of the current config and calibration
+#[post("/runs", data = "<request>")]
+pub fn start_run(
+    request: Json<runs::Request>,
+    runs: &State<Runs>,
+    config: &State<Config>,
+    measurements: &State<Measurements>,
+) -> Result<Json<Run>, (Status, String)> {
+    let calibration = measurements
+        .temperatures
+        .lock()
+        .expect("BUG: Failed to acquire temperatures lock")
+        .calibration
+        .clone();
+    runs.start(request.into_inner(), Snapshot::new(config, calibration))
+        .map(Json)
+        .map_err(runs_error)
+}
+
+#[post("/runs/<id>/stop")]
+pub fn stop_run(id: u64, runs: &State<Runs>) -> Result<Json<Run>, (Status, String)> {
+    runs.stop(id).map(Json).map_err(runs_error)
+}
+
+/// Health of the station, all the backends are up or at least one is degraded
+#[derive(Serialize)]
+pub struct HealthReport {
+    healthy: bool,
+    backends: BTreeMap<&'static str, Backend>,
+}
+
+/// Health of all the backends, 503 when any of them is not up
+#[get("/health")]
+pub fn health(health: &State<Health>) -> (Status, Json<HealthReport>) {
+    let report = HealthReport {
+        healthy: health.is_healthy(),
+        backends: health.backends(),
+    };
+    let status = match report.healthy {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+    (status, Json(report))
+}
+
+fn waveform_error(err: scpi::Error) -> (Status, String) {
+    let status = match err {
+        scpi::Error::Unsupported => Status::NotImplemented,
+        _ if err.is_connection_lost() => Status::ServiceUnavailable,
+        _ => Status::BadGateway,
+    };
+    (status, err.to_string())
+}
+
+/// Waveform of the channel of the named scope as JSON, CSV or SVG, JSON by default,
+/// at most `points` points, 1000 by default
+#[get("/instruments/<name>/waveform/<channel>?<format>&<points>")]
+pub async fn instrument_waveform(
+    name: &str,
+    channel: u8,
+    format: Option<waveform::Format>,
+    points: Option<usize>,
+    captures: &State<BTreeMap<String, Capture>>,
+) -> Result<(ContentType, String), (Status, String)> {
+    let Some(capture) = captures.get(name) else {
+        return Err((
+            Status::NotFound,
+            format!("Instrument {name} is not configured"),
+        ));
+    };
+    if !(1..=ScopeChannel::COUNT).contains(&channel) {
+        return Err((
+            Status::NotFound,
+            format!("Scope channel {channel} does not exist"),
+        ));
+    }
+    let points = points.unwrap_or(Waveform::DEFAULT_POINTS);
+    if !(1..=Waveform::MAX_POINTS).contains(&points) {
+        return Err((
+            Status::BadRequest,
+            format!("Points must be in range 1 - {}", Waveform::MAX_POINTS),
+        ));
+    }
+
+    let waveform = capture
+        .waveform(channel, points)
+        .await
+        .map_err(waveform_error)?;
+    Ok(match format.unwrap_or(waveform::Format::Json) {
+        waveform::Format::Json => (
+            ContentType::JSON,
+            serde_json::to_string(&waveform).expect("BUG: Failed to serialize waveform"),
+        ),
+        waveform::Format::Csv => (ContentType::CSV, waveform.csv()),
+        waveform::Format::Svg => (ContentType::SVG, waveform.svg()),
+    })
+}
@@ -10,4 +10,13 @@ pub struct Cli {
     /// Calibrate all temperature sensors to the supplied temperature
     #[arg(long)]
     pub calibrate: Option<f64>,
+
+    /// Replace all the hardware with synthetic sources from the `[simulation]` section
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// Start recording a session with the given name right away,
+    /// it is stopped when the station exits, e.g. on Ctrl-C
+    #[arg(long)]
+    pub record: Option<String>,
 }
@@ -1,29 +1,58 @@
 use crate::ds18b20;
+use crate::history;
 use crate::kalman;
 use crate::max6675;
 use crate::netio;
+use crate::recorder;
+use crate::runs;
 use crate::scope;
-use serde::Deserialize;
+use crate::simulation;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Read;
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Config {
-    pub scope: scope::Descriptor,
+    /// Scopes and the other SCPI instruments, every one is named differently
+    #[serde(default)]
+    pub instruments: Vec<scope::Descriptor>,
     pub ds18b20: ds18b20::Descriptor,
     pub sensors: max6675::Descriptor,
     pub kalman: kalman::Descriptor,
     pub netio: netio::Descriptor,
+    #[serde(default)]
+    pub history: history::Descriptor,
+    #[serde(default)]
+    pub recorder: recorder::Descriptor,
+    #[serde(default)]
+    pub runs: runs::Descriptor,
+    #[serde(default)]
+    pub simulation: simulation::Descriptor,
 }
 
 impl Config {
-    pub fn load(path: &str) -> Self {
-        let mut config_file = File::open(path).expect("BUG: Failed to open configuration file");
+    pub fn load(path: &str) -> Result<Self> {
+        let mut config_file = File::open(path)
+            .with_context(|| format!("Failed to open configuration file {path}"))?;
         let mut buffer = String::new();
         config_file
             .read_to_string(&mut buffer)
-            .expect("BUG: Failed to read config file");
+            .context("Failed to read configuration file")?;
 
-        toml::from_str(&buffer).expect("Failed to parse configuration file")
+        let config: Self = toml::from_str(&buffer).context("Failed to parse configuration file")?;
+        config.validate().context("Invalid configuration")?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.sensors.validate()?;
+        scope::Descriptor::validate_all(&self.instruments)?;
+        self.netio.validate()?;
+        self.simulation.validate()?;
+        self.sensors.polling.validate("sensors")?;
+        self.ds18b20.polling.validate("ds18b20")?;
+        self.netio.polling.validate("netio")?;
+        Ok(())
     }
 }
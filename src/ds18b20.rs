@@ -1,18 +1,52 @@
-use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use crate::measurement::{Measurement, Polling};
+use crate::scheduler::{TaskContext, Ticker};
+use crate::simulation::{self, Signal, Waveform};
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use tokio::time::{sleep, Duration};
+use std::time::SystemTime;
+use thiserror::Error;
 
-struct DS18B20 {
-    path: PathBuf,
+enum Source {
+    Sysfs(PathBuf),
+    Simulated(Signal),
 }
 
-#[derive(Clone, Deserialize, Debug)]
+pub struct DS18B20 {
+    source: Source,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The sysfs entry disappears when the sensor is disconnected
+    #[error("Sensor is not present")]
+    NotPresent,
+    #[error("Failed to read sensor, {0}")]
+    Io(io::Error),
+    #[error("CRC check failed")]
+    CrcFailure,
+    #[error("Failed to parse temperature from {0:?}")]
+    Parse(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotPresent,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Descriptor {
-    id: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub polling: Polling,
 }
 
 impl DS18B20 {
@@ -21,43 +55,78 @@ impl DS18B20 {
 
     pub fn open(id: &str) -> Self {
         let path = Path::new(Self::BASE_PATH).join(id).join(Self::TARGET);
-        Self { path }
+        Self {
+            source: Source::Sysfs(path),
+        }
     }
 
-    pub fn read_temp(&self) -> Result<f64> {
-        let buffer = fs::read_to_string(&self.path)?;
-        let lines: Vec<_> = buffer.trim().split('\n').collect();
+    pub fn simulated(waveform: &Waveform) -> Self {
+        Self {
+            source: Source::Simulated(Signal::new(waveform)),
+        }
+    }
 
-        let crc_check = lines.first().expect("BUG: Failed to get data");
+    pub fn read_temp(&mut self) -> Result<f64, Error> {
+        let buffer = match &mut self.source {
+            Source::Sysfs(path) => fs::read_to_string(path)?,
+            Source::Simulated(signal) => simulation::w1_slave(signal.sample()),
+        };
+        let mut lines = buffer.trim().split('\n');
+
+        let crc_check = lines.next().unwrap_or_default();
         if !crc_check.contains("YES") {
-            return Err(anyhow!("Failed to obtain valid data"));
+            return Err(Error::CrcFailure);
         }
 
-        let value = lines.last().expect("BUG: Failed to get data");
-        let value: Vec<_> = value.split('=').collect();
-        let value = value.last().expect("BUG: Failed to get temperature");
-        let value = f64::from_str(value)?;
+        let value = lines
+            .next_back()
+            .and_then(|line| line.split('=').next_back())
+            .ok_or_else(|| Error::Parse(buffer.clone()))?;
+        let value = f64::from_str(value).map_err(|_| Error::Parse(buffer.clone()))?;
 
         Ok(value / 1000.0)
     }
 }
 
 pub async fn update_temp_periodically(
-    descriptor: Descriptor,
-    ambient_temperature: Arc<Mutex<Option<f64>>>,
+    mut temp: DS18B20,
+    ambient_temperature: Arc<Mutex<Measurement>>,
+    mut ticker: Ticker,
+    context: TaskContext,
 ) {
-    const UPDATE_PERIOD_MS: Duration = Duration::from_millis(400);
-
-    let temp = DS18B20::open(&descriptor.id);
+    let TaskContext {
+        registry,
+        samples,
+        health,
+    } = context;
+    let stale_after = ambient_temperature
+        .lock()
+        .expect("BUG: Failed to acquire ambient_temperature lock")
+        .stale_after;
+    let temperature_gauge = registry
+        .gauge(
+            "ds18b20_temperature_c",
+            "Ambient temperature measured by the DS18B20 in ˚C",
+        )
+        .stale_after(stale_after);
 
     loop {
-        let temperature_reading = temp.read_temp().ok();
-        {
-            let mut temperature = ambient_temperature
-                .lock()
-                .expect("BUG: Failed to acquire ambient_temperature lock");
-            *temperature = temperature_reading;
-        }
-        sleep(UPDATE_PERIOD_MS).await;
+        ticker.tick().await;
+        let acquired = SystemTime::now();
+        let result = temp.read_temp();
+        health.report("ds18b20", result.as_ref().err());
+        let temperature_reading = match result {
+            Ok(temperature) => Some(temperature),
+            Err(err) => {
+                warn!("Failed to read ambient temperature, {err}");
+                None
+            }
+        };
+        ambient_temperature
+            .lock()
+            .expect("BUG: Failed to acquire ambient_temperature lock")
+            .update(temperature_reading, acquired);
+        temperature_gauge.set_or_remove_at(&[], temperature_reading, acquired);
+        samples.publish("ambient/temperature", "˚C", temperature_reading, acquired);
     }
 }
@@ -0,0 +1,141 @@
+use crate::history::Range;
+use crate::recorder::csv_field;
+use rocket::FromFormField;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+/// Wide has a column per series, long has a row per sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum Layout {
+    Wide,
+    Long,
+}
+
+/// Column header with the series name and its unit
+fn header(range: &Range) -> String {
+    csv_field(&format!("{} [{}]", range.series, range.unit)).into_owned()
+}
+
+fn format_value(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Rows of the values with the same timestamp, the series missing at the time are `None`,
+/// the ranges are expected to be downsampled with the same step, so the timestamps match
+fn rows(ranges: &[Range]) -> BTreeMap<i64, (f64, Vec<Option<f64>>)> {
+    let mut rows = BTreeMap::new();
+    for (column, range) in ranges.iter().enumerate() {
+        for (timestamp, value) in &range.points {
+            // Milliseconds are precise enough to match the bucket starts
+            let key = (timestamp * 1000.0).round() as i64;
+            let (_, values) = rows
+                .entry(key)
+                .or_insert_with(|| (*timestamp, vec![None; ranges.len()]));
+            values[column] = *value;
+        }
+    }
+    rows
+}
+
+pub fn render(ranges: &[Range], format: Format, layout: Layout) -> String {
+    match (format, layout) {
+        (Format::Csv, Layout::Long) => {
+            let mut csv = String::from("timestamp,series,unit,value\n");
+            for range in ranges {
+                for (timestamp, value) in &range.points {
+                    let value = format_value(*value);
+                    writeln!(
+                        csv,
+                        "{timestamp:.3},{},{},{value}",
+                        csv_field(&range.series),
+                        csv_field(range.unit)
+                    )
+                    .expect("BUG: Failed to write CSV");
+                }
+            }
+            csv
+        }
+        (Format::Csv, Layout::Wide) => {
+            let mut csv = String::from("timestamp");
+            for range in ranges {
+                write!(csv, ",{}", header(range)).expect("BUG: Failed to write CSV");
+            }
+            csv.push('\n');
+            for (timestamp, values) in rows(ranges).values() {
+                write!(csv, "{timestamp:.3}").expect("BUG: Failed to write CSV");
+                for value in values {
+                    write!(csv, ",{}", format_value(*value)).expect("BUG: Failed to write CSV");
+                }
+                csv.push('\n');
+            }
+            csv
+        }
+        (Format::Json, Layout::Long) => {
+            let samples: Vec<_> = ranges
+                .iter()
+                .flat_map(|range| {
+                    range.points.iter().map(|(timestamp, value)| {
+                        json!({
+                            "timestamp": timestamp,
+                            "series": range.series,
+                            "unit": range.unit,
+                            "value": value,
+                        })
+                    })
+                })
+                .collect();
+            json!(samples).to_string()
+        }
+        (Format::Json, Layout::Wide) => {
+            let columns: Vec<_> = ranges
+                .iter()
+                .map(|range| json!({ "series": range.series, "unit": range.unit }))
+                .collect();
+            let rows: Vec<_> = rows(ranges)
+                .into_values()
+                .map(|(timestamp, values)| json!({ "timestamp": timestamp, "values": values }))
+                .collect();
+            json!({ "columns": columns, "rows": rows }).to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_wide_columns() {
+        let ranges = [
+            Range {
+                series: "sensors/tc0/raw".to_owned(),
+                unit: "˚C",
+                points: vec![(10.0, Some(25.5)), (11.0, None), (12.0, Some(26.0))],
+            },
+            Range {
+                series: "netio/power".to_owned(),
+                unit: "W",
+                points: vec![(11.0, Some(40.0))],
+            },
+        ];
+
+        assert_eq!(
+            render(&ranges, Format::Csv, Layout::Wide),
+            "timestamp,sensors/tc0/raw [˚C],netio/power [W]\n\
+             10.000,25.5,\n\
+             11.000,,40\n\
+             12.000,26,\n"
+        );
+        assert_eq!(
+            render(&ranges[1..], Format::Csv, Layout::Long),
+            "timestamp,series,unit,value\n11.000,netio/power,W,40\n"
+        );
+    }
+}
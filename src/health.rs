@@ -0,0 +1,188 @@
+use crate::measurement::unix_seconds;
+use crate::registry::{Counter, Gauge, Registry};
+use log::{error, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{sleep, Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Backend is not connected yet, the connection is being retried
+    Connecting,
+    Up,
+    /// Backend is connected, but its reads fail
+    Failing,
+    /// Backend can not work with the configuration, it is not retried until restart
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Backend {
+    pub state: State,
+    /// Unix time of the last state change
+    pub since: f64,
+    /// Last error, cleared when the backend recovers
+    pub error: Option<String>,
+}
+
+/// Health of all the backends, a failing backend does not stop the others
+#[derive(Clone)]
+pub struct Health {
+    backends: Arc<Mutex<BTreeMap<&'static str, Backend>>>,
+    up: Gauge,
+    connect_failures: Counter,
+}
+
+impl Health {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            backends: Arc::new(Mutex::new(BTreeMap::new())),
+            up: registry.gauge(
+                "backend_up",
+                "Whether the backend is connected and its last read succeeded",
+            ),
+            connect_failures: registry.counter(
+                "backend_connect_failures_total",
+                "Number of failed attempts to connect the backend",
+            ),
+        }
+    }
+
+    pub fn backends(&self) -> BTreeMap<&'static str, Backend> {
+        self.backends
+            .lock()
+            .expect("BUG: Failed to acquire health lock")
+            .clone()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.backends()
+            .values()
+            .all(|backend| backend.state == State::Up)
+    }
+
+    fn set(&self, backend: &'static str, state: State, error: Option<String>) {
+        let mut backends = self
+            .backends
+            .lock()
+            .expect("BUG: Failed to acquire health lock");
+        let since = match backends.get(backend) {
+            Some(current) if current.state == state => current.since,
+            _ => {
+                info!("Backend {backend} is {state:?}");
+                unix_seconds(SystemTime::now())
+            }
+        };
+        backends.insert(
+            backend,
+            Backend {
+                state,
+                since,
+                error,
+            },
+        );
+        self.up
+            .set(&[("backend", backend)], (state == State::Up) as u8 as f64);
+    }
+
+    /// Updates the health with the result of the last read of the backend
+    pub fn report(&self, backend: &'static str, error: Option<impl Display>) {
+        match error {
+            Some(err) => self.set(backend, State::Failing, Some(err.to_string())),
+            None => self.set(backend, State::Up, None),
+        }
+    }
+
+    /// Retries until the backend connects, the delay between the attempts grows exponentially
+    pub async fn connect<T, E, F, Fut>(&self, backend: &'static str, connect: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        match self.connect_unless(backend, |_| false, connect).await {
+            Ok(connection) => connection,
+            Err(_) => unreachable!("BUG: Connection is retried on every error"),
+        }
+    }
+
+    /// Retries like `connect`, but gives up on the errors another attempt can not fix,
+    /// e.g. a wrong instrument at the address, the backend is then failed
+    pub async fn connect_unless<T, E, F, Fut>(
+        &self,
+        backend: &'static str,
+        is_terminal: impl Fn(&E) -> bool,
+        mut connect: F,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        let mut backoff = Backoff::default();
+        self.set(backend, State::Connecting, None);
+        loop {
+            match connect().await {
+                Ok(connection) => {
+                    self.set(backend, State::Up, None);
+                    return Ok(connection);
+                }
+                Err(err) if is_terminal(&err) => {
+                    error!("Failed to connect {backend}, {err}, not retrying");
+                    self.connect_failures.inc(&[("backend", backend)]);
+                    self.set(backend, State::Failed, Some(err.to_string()));
+                    return Err(err);
+                }
+                Err(err) => {
+                    let delay = backoff.next();
+                    warn!("Failed to connect {backend}, {err}, retrying in {delay:?}");
+                    self.connect_failures.inc(&[("backend", backend)]);
+                    self.set(backend, State::Connecting, Some(err.to_string()));
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+/// Exponentially growing delay between the connection attempts
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: Self::INITIAL,
+        }
+    }
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    /// Delay before the next attempt, doubled every time up to the maximum
+    pub fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(Self::MAX);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_delay_up_to_maximum() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+}
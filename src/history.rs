@@ -0,0 +1,203 @@
+use crate::measurement::{Sample, Samples};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct Descriptor {
+    /// Maximal number of samples kept per series
+    pub depth: usize,
+    /// Samples older than this are dropped
+    pub retention_s: f64,
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Self {
+            depth: 10000,
+            retention_s: 3600.0,
+        }
+    }
+}
+
+/// Timestamp and value, the value is `None` when the read failed
+pub type Point = (f64, Option<f64>);
+
+struct Series {
+    unit: &'static str,
+    points: VecDeque<Point>,
+}
+
+/// Queried series with the points downsampled to the requested step
+#[derive(Serialize, Debug)]
+pub struct Range {
+    pub series: String,
+    pub unit: &'static str,
+    pub points: Vec<Point>,
+}
+
+/// Bounded in-memory history of every series published by the periodic tasks
+#[derive(Clone)]
+pub struct History {
+    descriptor: Descriptor,
+    series: Arc<Mutex<BTreeMap<String, Series>>>,
+}
+
+impl History {
+    pub fn new(descriptor: &Descriptor) -> Self {
+        Self {
+            descriptor: descriptor.clone(),
+            series: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn insert(&self, sample: Sample) {
+        let mut series = self
+            .series
+            .lock()
+            .expect("BUG: Failed to acquire history lock");
+        let series = series.entry(sample.series).or_insert_with(|| Series {
+            unit: sample.unit,
+            points: VecDeque::new(),
+        });
+
+        series.points.push_back((sample.timestamp, sample.value));
+        let oldest = sample.timestamp - self.descriptor.retention_s;
+        while series.points.len() > self.descriptor.depth
+            || series.points.front().is_some_and(|(t, _)| *t < oldest)
+        {
+            series.points.pop_front();
+        }
+    }
+
+    /// Names of all the series recorded so far
+    pub fn names(&self) -> Vec<String> {
+        let series = self
+            .series
+            .lock()
+            .expect("BUG: Failed to acquire history lock");
+        series.keys().cloned().collect()
+    }
+
+    /// Points in the time range, with `step` the values are averaged over the step long buckets
+    pub fn query(&self, name: &str, from: f64, to: f64, step: Option<f64>) -> Option<Range> {
+        let series = self
+            .series
+            .lock()
+            .expect("BUG: Failed to acquire history lock");
+        let series = series.get(name)?;
+        let points = series
+            .points
+            .iter()
+            .filter(|(t, _)| (from..=to).contains(t))
+            .copied();
+
+        let points = match step.filter(|step| *step > 0.0) {
+            Some(step) => downsample(points, from, step),
+            None => points.collect(),
+        };
+
+        Some(Range {
+            series: name.to_owned(),
+            unit: series.unit,
+            points,
+        })
+    }
+}
+
+/// Averages the values in every bucket, bucket is `None` when all its reads failed
+fn downsample(points: impl Iterator<Item = Point>, from: f64, step: f64) -> Vec<Point> {
+    let mut buckets: Vec<Point> = Vec::new();
+    let mut bucket: Option<(u64, f64, usize)> = None;
+
+    for (t, value) in points {
+        let index = ((t - from) / step) as u64;
+        if let Some((current, sum, count)) = bucket {
+            if current != index {
+                buckets.push(average(from, step, current, sum, count));
+                bucket = None;
+            }
+        }
+        let (_, sum, count) = bucket.get_or_insert((index, 0.0, 0));
+        if let Some(value) = value {
+            *sum += value;
+            *count += 1;
+        }
+    }
+    if let Some((current, sum, count)) = bucket {
+        buckets.push(average(from, step, current, sum, count));
+    }
+    buckets
+}
+
+fn average(from: f64, step: f64, index: u64, sum: f64, count: usize) -> Point {
+    let value = (count > 0).then(|| sum / count as f64);
+    (from + index as f64 * step, value)
+}
+
+/// Stores every published sample into the history
+pub async fn record_periodically(history: History, samples: Samples) {
+    let mut receiver = samples.subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok(sample) => history.insert(sample),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("History is too slow, {skipped} samples skipped")
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: f64, value: Option<f64>) -> Sample {
+        Sample {
+            series: "netio/power".to_owned(),
+            unit: "W",
+            value,
+            timestamp,
+            run: None,
+        }
+    }
+
+    #[test]
+    fn drops_old_samples() {
+        let history = History::new(&Descriptor {
+            depth: 3,
+            retention_s: 10.0,
+        });
+        for t in 0..5 {
+            history.insert(sample(t as f64, Some(t as f64)));
+        }
+        history.insert(sample(14.0, Some(14.0)));
+
+        let range = history.query("netio/power", 0.0, 100.0, None).unwrap();
+        assert_eq!(range.points, vec![(4.0, Some(4.0)), (14.0, Some(14.0))]);
+        assert!(history.query("netio/voltage", 0.0, 100.0, None).is_none());
+    }
+
+    #[test]
+    fn averages_buckets() {
+        let history = History::new(&Descriptor::default());
+        history.insert(sample(100.0, Some(1.0)));
+        history.insert(sample(100.5, Some(3.0)));
+        history.insert(sample(101.0, None));
+        history.insert(sample(102.2, None));
+        history.insert(sample(102.4, Some(5.0)));
+        history.insert(sample(103.9, None));
+
+        let range = history
+            .query("netio/power", 100.0, 103.5, Some(1.0))
+            .unwrap();
+        assert_eq!(
+            range.points,
+            vec![(100.0, Some(2.0)), (101.0, None), (102.0, Some(5.0))]
+        );
+    }
+}
@@ -1,162 +1,216 @@
+mod api;
 mod args;
 mod config;
 mod ds18b20;
+mod export;
+mod health;
+mod history;
 mod kalman;
+mod max31855;
+mod max31856;
 mod max6675;
+mod measurement;
 mod netio;
+mod recorder;
+mod registry;
+mod rigol;
+mod runs;
+mod scheduler;
 mod scope;
+mod scpi;
+mod siglent;
+mod simulation;
 mod spi;
+mod waveform;
 
 use anyhow::Result;
 use clap::Parser;
+use health::Health;
+use history::History;
 use log::info;
 use max6675::Temperatures;
+use measurement::{Measurement, Measurements, Samples};
+use recorder::Recorder;
+use registry::Registry;
+use rocket::http::ContentType;
+use rocket::response::content::RawHtml;
 use rocket::State;
+use runs::Runs;
+use scheduler::{Scheduler, TaskContext};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use waveform::Capture;
 
 #[macro_use]
 extern crate rocket;
 
-struct Measurements {
-    temperatures: Arc<Mutex<Temperatures>>,
-    ambient_temperature: Arc<Mutex<Option<f64>>>,
-    psu_voltage: Arc<Mutex<Option<f64>>>,
-    fan_rpm: Arc<Mutex<Option<f64>>>,
-    power: Arc<Mutex<Option<f64>>>,
-}
-
 #[get("/metrics")]
-async fn metrics(measurements: &State<Measurements>) -> String {
-    let mut metrics = String::new();
-
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("BUG: Failed to get current time")
-        .as_millis();
-
-    if let Some(psu_voltage) = *measurements
-        .psu_voltage
-        .lock()
-        .expect("BUG: Failed to acquire psu_voltage lock")
-    {
-        metrics.push_str(&format!("scope_voltage_v {psu_voltage:.2} {time}\n"));
-    }
-
-    if let Some(fan_rpm) = *measurements
-        .fan_rpm
-        .lock()
-        .expect("BUG: Failed to acquire fan_rpm lock")
-    {
-        metrics.push_str(&format!("scope_fan_rpm {fan_rpm:.0} {time}\n"));
-    }
-
-    if let Some(ambient_temperature) = *measurements
-        .ambient_temperature
-        .lock()
-        .expect("BUG: Failed to acquire ambient_temperature lock")
-    {
-        metrics.push_str(&format!(
-            "ds18b20_temperature_c {ambient_temperature:.2} {time}\n"
-        ));
-    }
-
-    if let Some(power) = *measurements
-        .power
-        .lock()
-        .expect("BUG: Failed to acquire ambient_temperature lock")
-    {
-        metrics.push_str(&format!("netio_power_w {power:.0} {time}\n"));
-    }
-
-    let temperatures = measurements
-        .temperatures
-        .lock()
-        .expect("BUG: Failed to acquire temperatures lock");
-
-    for (sensor_id, temp) in temperatures.inner.iter() {
-        if let Some(calibration_offset) = temperatures.calibration.get(sensor_id) {
-            let temp = temp + calibration_offset;
-            metrics.push_str(&format!(
-                "max6675_temperature_c{{sensor_id=\"{sensor_id}\"}} {temp:.2} {time}\n"
-            ));
-        }
-    }
-
-    for (sensor_id, filtered) in temperatures.filtered.iter() {
-        if let Some(calibration_offset) = temperatures.calibration.get(sensor_id) {
-            let temp = filtered.value() + calibration_offset;
-            metrics.push_str(&format!(
-                "max6675_temperature_filtered_c{{sensor_id=\"{sensor_id}\"}} {temp:.2} {time}\n"
-            ));
-        }
-    }
-
-    for (sensor_id, temp) in temperatures.inner.iter() {
-        metrics.push_str(&format!(
-            "max6675_temperature_raw_c{{sensor_id=\"{sensor_id}\"}} {temp:.2} {time}\n"
-        ));
-    }
+async fn metrics(registry: &State<Registry>) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params([("version", "0.0.4")]);
+    (content_type, registry.render())
+}
 
-    metrics
+/// Self-contained page with live charts of all the measurements
+#[get("/")]
+fn dashboard() -> RawHtml<&'static str> {
+    RawHtml(include_str!("../resources/dashboard.html"))
 }
 
 #[rocket::main]
-async fn main() -> Result<(), rocket::Error> {
+async fn main() -> Result<()> {
     env_logger::init();
     let args = args::Cli::parse();
-    let config = config::Config::load(&args.config);
+    let config = config::Config::load(&args.config)?;
+
+    let simulation = args.simulate.then(|| config.simulation.clone());
+    if simulation.is_some() {
+        info!("Running in simulation mode, no hardware is used");
+    }
 
     if let Some(real_temp) = args.calibrate {
         info!("Calibrating sensors to temperature {} ˚C", real_temp);
-        max6675::calibrate_sensors(config.sensors.clone(), real_temp, &config.kalman)
+        let sensors = max6675::connect_sensors(&config.sensors, simulation.as_ref())?;
+        max6675::calibrate_sensors(sensors, config.sensors.clone(), real_temp, &config.kalman)
             .await
             .expect("BUG: Failed to calibrate sensors");
         return Ok(());
     }
 
-    let mut temperatures = Temperatures::new(config.sensors.num_sensors, &config.kalman);
+    let mut temperatures = Temperatures::new(&config.sensors, &config.kalman);
     temperatures
-        .load_calibration(&config.sensors.calibration_file)
+        .load_calibration(&config.sensors)
         .unwrap_or_else(|_| warn!("Failed to load calibration"));
     let temperatures = Arc::new(Mutex::new(temperatures));
 
-    let ambient_temperature = Arc::new(Mutex::new(None));
-    let psu_voltage = Arc::new(Mutex::new(None));
-    let fan_rpm = Arc::new(Mutex::new(None));
-    let power = Arc::new(Mutex::new(None));
-
     let measurements = Measurements {
-        temperatures: temperatures.clone(),
-        ambient_temperature: ambient_temperature.clone(),
-        psu_voltage: psu_voltage.clone(),
-        fan_rpm: fan_rpm.clone(),
-        power: power.clone(),
+        temperatures,
+        ambient_temperature: Arc::new(Mutex::new(Measurement::new(
+            config.ds18b20.polling.stale_after(),
+        ))),
+        instruments: config
+            .instruments
+            .iter()
+            .map(|instrument| {
+                let measurements = instrument
+                    .measurements
+                    .iter()
+                    .map(|query| {
+                        let measurement = Measurement::new(instrument.polling.stale_after());
+                        (query.name.clone(), Arc::new(Mutex::new(measurement)))
+                    })
+                    .collect();
+                (instrument.name.clone(), measurements)
+            })
+            .collect(),
+        power: Arc::new(Mutex::new(Measurement::new(
+            config.netio.polling.stale_after(),
+        ))),
+    };
+
+    let registry = Registry::new();
+    let scheduler = Scheduler::new(&registry);
+    let health = Health::new(&registry);
+    let samples = Samples::new();
+    let history = History::new(&config.history);
+    let runs = Runs::load(&config.runs, samples.clone())?;
+    let recorder = Recorder::new(&config.recorder);
+    if let Some(name) = &args.record {
+        recorder.start(name)?;
+    }
+
+    let context = TaskContext {
+        registry: registry.clone(),
+        samples: samples.clone(),
+        health: health.clone(),
+    };
+    let ambient_sensor = match &simulation {
+        Some(simulation) => ds18b20::DS18B20::simulated(&simulation.ambient_temperature),
+        None => ds18b20::DS18B20::open(&config.ds18b20.id),
+    };
+    let netio = match &simulation {
+        Some(simulation) => netio::Netio::simulated(&config.netio, &simulation.power),
+        None => netio::Netio::new(&config.netio),
     };
 
+    tokio::spawn(history::record_periodically(
+        history.clone(),
+        samples.clone(),
+    ));
+    tokio::spawn(recorder::record_periodically(
+        recorder.clone(),
+        samples.clone(),
+    ));
     tokio::spawn(max6675::update_temp_periodically(
         config.sensors.clone(),
-        temperatures.clone(),
+        simulation.clone(),
+        measurements.temperatures.clone(),
+        scheduler.ticker("max6675", config.sensors.polling.poll_interval()),
+        context.clone(),
     ));
     tokio::spawn(ds18b20::update_temp_periodically(
-        config.ds18b20.clone(),
-        ambient_temperature.clone(),
-    ));
-    tokio::spawn(scope::update_voltage_periodically(
-        config.scope.clone(),
-        psu_voltage.clone(),
-        fan_rpm.clone(),
+        ambient_sensor,
+        measurements.ambient_temperature.clone(),
+        scheduler.ticker("ds18b20", config.ds18b20.polling.poll_interval()),
+        context.clone(),
     ));
+    let mut captures = BTreeMap::new();
+    for instrument in &config.instruments {
+        let (capture, capture_requests) = Capture::new();
+        captures.insert(instrument.name.clone(), capture);
+        let task = Box::leak(instrument.name.clone().into_boxed_str());
+        tokio::spawn(scope::update_measurements_periodically(
+            instrument.clone(),
+            simulation.clone(),
+            measurements.instruments[&instrument.name].clone(),
+            capture_requests,
+            scheduler.ticker(task, instrument.polling.poll_interval()),
+            context.clone(),
+        ));
+    }
     tokio::spawn(netio::update_power_periodically(
-        config.netio.clone(),
-        power.clone(),
+        netio,
+        measurements.power.clone(),
+        scheduler.ticker("netio", config.netio.polling.poll_interval()),
+        context.clone(),
     ));
 
     let _rocket = rocket::build()
-        .mount("/", routes![metrics])
+        .mount("/", routes![dashboard, metrics])
+        .mount(
+            "/api/v1",
+            routes![
+                api::readings,
+                api::sensor,
+                api::stream,
+                api::series,
+                api::history,
+                api::export,
+                api::recording,
+                api::start_recording,
+                api::stop_recording,
+                api::list_runs,
+                api::get_run,
+                api::start_run,
+                api::stop_run,
+                api::health,
+                api::instrument_waveform
+            ],
+        )
+        .manage(registry)
         .manage(measurements)
+        .manage(samples)
+        .manage(history)
+        .manage(recorder.clone())
+        .manage(runs)
+        .manage(config)
+        .manage(health)
+        .manage(captures)
         .launch()
         .await?;
 
+    // Session started from the command line ends with the station
+    if recorder.status().is_some() {
+        recorder.stop()?;
+    }
     Ok(())
 }
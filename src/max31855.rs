@@ -0,0 +1,180 @@
+use crate::max6675::{Chip, Converter, Error, Reading};
+use crate::spi::SpiDevice;
+use thiserror::Error;
+
+/// Raw 32-bit frame clocked out of the MAX31855
+///
+/// D31 - D18 14-bit signed thermocouple temperature, 0.25 ˚C per LSB
+/// D17 reserved
+/// D16 any fault is present
+/// D15 - D4 12-bit signed cold-junction temperature, 0.0625 ˚C per LSB
+/// D3 reserved
+/// D2 thermocouple is shorted to VCC
+/// D1 thermocouple is shorted to GND
+/// D0 thermocouple input is open
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame(u32);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("Sensor connection is probably wrong, frame 0x{0:08X}")]
+    BusStuckHigh(u32),
+    #[error("Sensor is probably not present, frame 0x{0:08X}")]
+    NotPresent(u32),
+    #[error("Thermocouple is probably not connected, frame 0x{0:08X}")]
+    OpenThermocouple(u32),
+    #[error("Thermocouple is shorted to GND, frame 0x{0:08X}")]
+    ShortToGround(u32),
+    #[error("Thermocouple is shorted to VCC, frame 0x{0:08X}")]
+    ShortToVcc(u32),
+    #[error("Fault bit D16 is set without any fault indicated, frame 0x{0:08X}")]
+    UnknownFault(u32),
+}
+
+impl FrameError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::BusStuckHigh(_) => "bus_stuck_high",
+            Self::NotPresent(_) => "not_present",
+            Self::OpenThermocouple(_) => "open_thermocouple",
+            Self::ShortToGround(_) => "short_to_gnd",
+            Self::ShortToVcc(_) => "short_to_vcc",
+            Self::UnknownFault(_) => "fault",
+        }
+    }
+}
+
+impl Frame {
+    const FAULT_BIT: u32 = 0x0001_0000;
+    const SHORT_TO_VCC_BIT: u32 = 0x0000_0004;
+    const SHORT_TO_GROUND_BIT: u32 = 0x0000_0002;
+    const OPEN_THERMOCOUPLE_BIT: u32 = 0x0000_0001;
+    const TEMPERATURE_RESOLUTION: f64 = 0.25;
+    const COLD_JUNCTION_RESOLUTION: f64 = 0.0625;
+
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self(u32::from_be_bytes(bytes))
+    }
+
+    pub fn fault(&self) -> bool {
+        self.0 & Self::FAULT_BIT != 0
+    }
+
+    /// Signed 14-bit thermocouple reading
+    pub fn raw_temperature(&self) -> i16 {
+        // Shift the sign bit to the top first, arithmetic shift extends it
+        ((self.0 >> 16) as i16) >> 2
+    }
+
+    /// Signed 12-bit cold-junction reading
+    pub fn raw_cold_junction(&self) -> i16 {
+        (self.0 as u16 as i16) >> 4
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.raw_temperature() as f64 * Self::TEMPERATURE_RESOLUTION
+    }
+
+    pub fn cold_junction(&self) -> f64 {
+        self.raw_cold_junction() as f64 * Self::COLD_JUNCTION_RESOLUTION
+    }
+
+    /// Checks all the fault indications and returns both temperatures in ˚C
+    pub fn decode(&self) -> Result<Reading, FrameError> {
+        match self.0 {
+            0xFFFF_FFFF => return Err(FrameError::BusStuckHigh(self.0)),
+            0x0000_0000 => return Err(FrameError::NotPresent(self.0)),
+            _ => {}
+        }
+        if self.0 & Self::OPEN_THERMOCOUPLE_BIT != 0 {
+            return Err(FrameError::OpenThermocouple(self.0));
+        }
+        if self.0 & Self::SHORT_TO_GROUND_BIT != 0 {
+            return Err(FrameError::ShortToGround(self.0));
+        }
+        if self.0 & Self::SHORT_TO_VCC_BIT != 0 {
+            return Err(FrameError::ShortToVcc(self.0));
+        }
+        if self.fault() {
+            return Err(FrameError::UnknownFault(self.0));
+        }
+
+        Ok(Reading {
+            temperature: self.temperature(),
+            cold_junction: Some(self.cold_junction()),
+        })
+    }
+}
+
+pub struct MAX31855 {
+    spi: SpiDevice,
+}
+
+impl MAX31855 {
+    const NUM_RESPONSE_BYTES: usize = 4;
+
+    pub fn new(spi: SpiDevice) -> Self {
+        Self { spi }
+    }
+}
+
+impl Converter for MAX31855 {
+    fn chip(&self) -> Chip {
+        Chip::MAX31855
+    }
+
+    fn read(&mut self) -> Result<Reading, Error> {
+        let mut value = [0_u8; Self::NUM_RESPONSE_BYTES];
+        self.spi.read(&mut value)?;
+
+        Ok(Frame::from_bytes(value).decode()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_temperatures() {
+        let reading = Frame(0x0640_1900).decode().unwrap();
+        assert_eq!(reading.temperature, 100.0);
+        assert_eq!(reading.cold_junction, Some(25.0));
+
+        let reading = Frame(0xFFFC_FF00).decode().unwrap();
+        assert_eq!(reading.temperature, -0.25);
+        assert_eq!(reading.cold_junction, Some(-1.0));
+    }
+
+    #[test]
+    fn detects_bus_faults() {
+        assert_eq!(
+            Frame(0xFFFF_FFFF).decode(),
+            Err(FrameError::BusStuckHigh(0xFFFF_FFFF))
+        );
+        assert_eq!(
+            Frame(0x0000_0000).decode(),
+            Err(FrameError::NotPresent(0x0000_0000))
+        );
+    }
+
+    #[test]
+    fn detects_thermocouple_faults() {
+        assert_eq!(
+            Frame(0x0001_1901).decode(),
+            Err(FrameError::OpenThermocouple(0x0001_1901))
+        );
+        assert_eq!(
+            Frame(0x0001_1902).decode(),
+            Err(FrameError::ShortToGround(0x0001_1902))
+        );
+        assert_eq!(
+            Frame(0x0001_1904).decode(),
+            Err(FrameError::ShortToVcc(0x0001_1904))
+        );
+        assert_eq!(
+            Frame(0x0641_1900).decode(),
+            Err(FrameError::UnknownFault(0x0641_1900))
+        );
+    }
+}
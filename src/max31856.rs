@@ -0,0 +1,273 @@
+use crate::max6675::{Chip, Converter, Error, Reading};
+use crate::spi::SpiDevice;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Thermocouple types supported by the MAX31856, the value is the CR1 TC TYPE code
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Thermocouple {
+    B = 0,
+    E = 1,
+    J = 2,
+    #[default]
+    K = 3,
+    N = 4,
+    R = 5,
+    S = 6,
+    T = 7,
+}
+
+/// Register addresses, MSB set means write access
+pub mod register {
+    pub const CR0: u8 = 0x00;
+    pub const CJTH: u8 = 0x0A;
+    pub const WRITE: u8 = 0x80;
+}
+
+/// Measurement registers CJTH, CJTL, LTCBH, LTCBM, LTCBL and SR read in one burst
+///
+/// CJTH - CJTL 14-bit signed cold-junction temperature, 0.015625 ˚C per LSB
+/// LTCBH - LTCBL 19-bit signed thermocouple temperature, 0.0078125 ˚C per LSB
+/// SR fault status, CJ range, TC range, CJ high, CJ low, TC high, TC low, OV/UV, open
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame([u8; Frame::LEN]);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("Sensor connection is probably wrong, frame {0:02X?}")]
+    BusStuckHigh([u8; Frame::LEN]),
+    /// Configuration can not be read back from the CR0 register,
+    /// or nobody drives MISO and the frame reads all zeros
+    #[error("Sensor is probably not present, reads 0x{0:02X}")]
+    NotPresent(u8),
+    #[error("Thermocouple is probably not connected, status 0x{0:02X}")]
+    OpenThermocouple(u8),
+    #[error("Thermocouple input over or under voltage, status 0x{0:02X}")]
+    OverUnderVoltage(u8),
+    #[error("Thermocouple temperature out of range, status 0x{0:02X}")]
+    ThermocoupleOutOfRange(u8),
+    #[error("Cold-junction temperature out of range, status 0x{0:02X}")]
+    ColdJunctionOutOfRange(u8),
+}
+
+impl FrameError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::BusStuckHigh(_) => "bus_stuck_high",
+            Self::NotPresent(_) => "not_present",
+            Self::OpenThermocouple(_) => "open_thermocouple",
+            Self::OverUnderVoltage(_) => "over_under_voltage",
+            Self::ThermocoupleOutOfRange(_) => "out_of_range",
+            Self::ColdJunctionOutOfRange(_) => "cold_junction_out_of_range",
+        }
+    }
+}
+
+impl Frame {
+    pub const LEN: usize = 6;
+    const OPEN_BIT: u8 = 0x01;
+    const OVUV_BIT: u8 = 0x02;
+    const TC_RANGE_BIT: u8 = 0x40;
+    const CJ_RANGE_BIT: u8 = 0x80;
+    const TEMPERATURE_RESOLUTION: f64 = 0.0078125;
+    const COLD_JUNCTION_RESOLUTION: f64 = 0.015625;
+
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn status(&self) -> u8 {
+        self.0[5]
+    }
+
+    /// Signed 19-bit thermocouple reading
+    pub fn raw_temperature(&self) -> i32 {
+        // Shift the sign bit to the top first, arithmetic shift extends it
+        i32::from_be_bytes([self.0[2], self.0[3], self.0[4], 0]) >> 13
+    }
+
+    /// Signed 14-bit cold-junction reading
+    pub fn raw_cold_junction(&self) -> i16 {
+        i16::from_be_bytes([self.0[0], self.0[1]]) >> 2
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.raw_temperature() as f64 * Self::TEMPERATURE_RESOLUTION
+    }
+
+    pub fn cold_junction(&self) -> f64 {
+        self.raw_cold_junction() as f64 * Self::COLD_JUNCTION_RESOLUTION
+    }
+
+    /// Checks the fault status register and returns both temperatures in ˚C
+    pub fn decode(&self) -> Result<Reading, FrameError> {
+        if self.0 == [0xFF; Self::LEN] {
+            return Err(FrameError::BusStuckHigh(self.0));
+        }
+        if self.0 == [0x00; Self::LEN] {
+            return Err(FrameError::NotPresent(0x00));
+        }
+
+        let status = self.status();
+        if status & Self::OPEN_BIT != 0 {
+            return Err(FrameError::OpenThermocouple(status));
+        }
+        if status & Self::OVUV_BIT != 0 {
+            return Err(FrameError::OverUnderVoltage(status));
+        }
+        if status & Self::TC_RANGE_BIT != 0 {
+            return Err(FrameError::ThermocoupleOutOfRange(status));
+        }
+        if status & Self::CJ_RANGE_BIT != 0 {
+            return Err(FrameError::ColdJunctionOutOfRange(status));
+        }
+
+        Ok(Reading {
+            temperature: self.temperature(),
+            cold_junction: Some(self.cold_junction()),
+        })
+    }
+}
+
+pub struct MAX31856 {
+    spi: SpiDevice,
+    thermocouple: Thermocouple,
+    configured: bool,
+}
+
+impl MAX31856 {
+    /// Automatic conversion every 100 ms with the open-circuit fault detection enabled
+    const CR0: u8 = 0x80 | 0x10;
+
+    pub fn new(spi: SpiDevice, thermocouple: Thermocouple) -> Self {
+        Self {
+            spi,
+            thermocouple,
+            configured: false,
+        }
+    }
+
+    /// Writes the configuration and checks it can be read back,
+    /// which is also the only way to find out the chip is present
+    fn configure(&mut self) -> Result<(), Error> {
+        let cr1 = self.thermocouple as u8;
+        self.spi
+            .write(&[register::WRITE | register::CR0, Self::CR0, cr1])?;
+
+        // CR1 of the type B thermocouple is zero, which a missing chip reads too
+        let mut rx_buf = [0_u8; 2];
+        self.spi.transfer(&[register::CR0, 0x00], &mut rx_buf)?;
+        if rx_buf[1] != Self::CR0 {
+            return Err(FrameError::NotPresent(rx_buf[1]).into());
+        }
+
+        self.configured = true;
+        Ok(())
+    }
+}
+
+impl Converter for MAX31856 {
+    fn chip(&self) -> Chip {
+        Chip::MAX31856
+    }
+
+    fn read(&mut self) -> Result<Reading, Error> {
+        if !self.configured {
+            self.configure()?;
+        }
+
+        let mut tx_buf = [0_u8; Frame::LEN + 1];
+        tx_buf[0] = register::CJTH;
+        let mut rx_buf = [0_u8; Frame::LEN + 1];
+        self.spi.transfer(&tx_buf, &mut rx_buf)?;
+
+        let mut value = [0_u8; Frame::LEN];
+        value.copy_from_slice(&rx_buf[1..]);
+        let reading = Frame::from_bytes(value).decode();
+        // Sensor may be replugged, configure it again before the next read
+        if let Err(FrameError::BusStuckHigh(_) | FrameError::NotPresent(_)) = reading {
+            self.configured = false;
+        }
+
+        Ok(reading?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{self, Shape, Waveform};
+    use crate::spi::{SharedBus, SimulatedBus};
+    use std::sync::{Arc, Mutex};
+
+    fn simulated(thermocouple: Waveform) -> MAX31856 {
+        let bus = SimulatedBus::new();
+        let cold_junction = Waveform::new(Shape::Constant { value: 25.0 });
+        bus.attach(14, simulation::MAX31856::new(&thermocouple, &cold_junction));
+        let spi: SharedBus = Arc::new(Mutex::new(bus.clone()));
+        let cs = Box::new(bus.chip_select(14));
+        let device = SpiDevice::new(spi, cs, Chip::MAX31856.spi_mode());
+        MAX31856::new(device, Thermocouple::K)
+    }
+
+    #[test]
+    fn decodes_temperatures() {
+        let reading = Frame([0x19, 0x00, 0x06, 0x40, 0x00, 0x00])
+            .decode()
+            .unwrap();
+        assert_eq!(reading.temperature, 100.0);
+        assert_eq!(reading.cold_junction, Some(25.0));
+
+        let reading = Frame([0xFF, 0x00, 0xFF, 0xFF, 0xE0, 0x00])
+            .decode()
+            .unwrap();
+        assert_eq!(reading.temperature, -0.0078125);
+        assert_eq!(reading.cold_junction, Some(-1.0));
+    }
+
+    #[test]
+    fn detects_faults() {
+        assert_eq!(
+            Frame([0xFF; Frame::LEN]).decode(),
+            Err(FrameError::BusStuckHigh([0xFF; Frame::LEN]))
+        );
+        assert_eq!(
+            Frame([0x00; Frame::LEN]).decode(),
+            Err(FrameError::NotPresent(0x00))
+        );
+        assert_eq!(
+            Frame([0x19, 0x00, 0x06, 0x40, 0x00, 0x01]).decode(),
+            Err(FrameError::OpenThermocouple(0x01))
+        );
+        assert_eq!(
+            Frame([0x19, 0x00, 0x06, 0x40, 0x00, 0x02]).decode(),
+            Err(FrameError::OverUnderVoltage(0x02))
+        );
+        assert_eq!(
+            Frame([0x19, 0x00, 0x06, 0x40, 0x00, 0x40]).decode(),
+            Err(FrameError::ThermocoupleOutOfRange(0x40))
+        );
+        assert_eq!(
+            Frame([0x19, 0x00, 0x06, 0x40, 0x00, 0x80]).decode(),
+            Err(FrameError::ColdJunctionOutOfRange(0x80))
+        );
+    }
+
+    #[test]
+    fn configures_and_reads_simulated_bus() {
+        let mut sensor = simulated(Waveform::new(Shape::Constant { value: 100.0 }));
+        let reading = sensor.read().unwrap();
+        assert!(sensor.configured);
+        assert_eq!(reading.temperature, 100.0);
+        assert_eq!(reading.cold_junction, Some(25.0));
+
+        let mut thermocouple = Waveform::new(Shape::Constant { value: 100.0 });
+        thermocouple.dropout = 1.0;
+        let mut sensor = simulated(thermocouple);
+        assert!(matches!(
+            sensor.read(),
+            Err(Error::MAX31856(FrameError::OpenThermocouple(0x01)))
+        ));
+    }
+}
@@ -1,179 +1,698 @@
 use crate::kalman::{self, Kalman};
-use crate::spi::Spi;
-use anyhow::{anyhow, Context, Result};
+use crate::max31855::{self, MAX31855};
+use crate::max31856::{self, MAX31856};
+use crate::measurement::{is_stale, unix_seconds, Polling, Timestamped};
+use crate::registry::{Counter, Gauge, Registry};
+use crate::scheduler::{TaskContext, Ticker};
+use crate::simulation;
+use crate::spi::{ChipSelect, GpioChipSelect, Mode, SharedBus, SimulatedBus, Spi, SpiDevice};
+use anyhow::{bail, Context, Result};
 use log::warn;
-use rppal::gpio::{Gpio, OutputPin};
-use serde::Deserialize;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{self, BufReader, Write};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use thiserror::Error;
 use tokio::time::{sleep, Duration};
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Descriptor {
-    pub num_sensors: usize,
-    pub cs_pins: Vec<usize>,
     pub calibration_file: String,
+    /// The poll interval can not be shorter than the conversion time
+    #[serde(flatten)]
+    pub polling: Polling,
+    pub channels: Vec<Channel>,
+}
+
+impl Descriptor {
+    /// Names identify the sensors in metrics and calibration, both names and pins must be unique
+    pub fn validate(&self) -> Result<()> {
+        if let Some(chip) = self
+            .channels
+            .iter()
+            .map(|channel| channel.chip)
+            .max_by_key(|chip| chip.conversion_time())
+        {
+            if self.polling.poll_interval() < chip.conversion_time() {
+                bail!(
+                    "Poll interval {} ms is shorter than {:?} conversion time {} ms",
+                    self.polling.poll_interval_ms,
+                    chip,
+                    chip.conversion_time().as_millis()
+                );
+            }
+        }
+
+        let mut names = BTreeSet::new();
+        let mut cs_pins = BTreeSet::new();
+        for channel in &self.channels {
+            if !names.insert(channel.name.as_str()) {
+                bail!("Sensor name {} is used more than once", channel.name);
+            }
+            if !cs_pins.insert(channel.cs_pin) {
+                bail!("CS pin {} is used more than once", channel.cs_pin);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Thermocouple converter chips supported by the station
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Chip {
+    #[default]
+    MAX6675,
+    MAX31855,
+    MAX31856,
+}
+
+impl Chip {
+    /// Minimal delay between two reads to get a new measurement
+    pub fn conversion_time(&self) -> Duration {
+        match self {
+            Self::MAX6675 => Duration::from_millis(220),
+            Self::MAX31855 => Duration::from_millis(100),
+            Self::MAX31856 => Duration::from_millis(100),
+        }
+    }
+
+    /// The MAX31856 samples SDI on the falling edge, unlike the read only chips
+    pub fn spi_mode(&self) -> Mode {
+        match self {
+            Self::MAX6675 | Self::MAX31855 => Mode::SPI_MODE_0,
+            Self::MAX31856 => Mode::SPI_MODE_1,
+        }
+    }
+}
+
+/// Entry of `[[sensors.channels]]`, the name is the stable identity of the sensor
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub cs_pin: usize,
+    #[serde(default)]
+    pub chip: Chip,
+    /// Used only by the MAX31856, other chips are K type only
+    #[serde(default)]
+    pub thermocouple: max31856::Thermocouple,
+    /// Where the thermocouple is attached, exported as a metric label
+    pub location: Option<String>,
+    /// Device under test the thermocouple belongs to, exported as a metric label
+    pub dut: Option<String>,
+    /// Calibration offset in ˚C, takes precedence over the calibration file
+    pub offset: Option<f64>,
+}
+
+impl Channel {
+    fn create(&self, spi: SpiDevice) -> Sensor {
+        let converter: Box<dyn Converter> = match self.chip {
+            Chip::MAX6675 => Box::new(MAX6675::new(spi)),
+            Chip::MAX31855 => Box::new(MAX31855::new(spi)),
+            Chip::MAX31856 => Box::new(MAX31856::new(spi, self.thermocouple)),
+        };
+        Sensor {
+            channel: self.clone(),
+            converter,
+        }
+    }
+
+    /// Metric labels identifying the sensor
+    pub fn labels(&self) -> Vec<(&'static str, &str)> {
+        let mut labels = vec![("sensor", self.name.as_str())];
+        if let Some(location) = &self.location {
+            labels.push(("location", location));
+        }
+        if let Some(dut) = &self.dut {
+            labels.push(("dut", dut));
+        }
+        labels
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub temperature: f64,
+    /// Internal temperature of the chip, not all the chips measure it
+    pub cold_junction: Option<f64>,
+}
+
+/// Failure of the sensor read, the faults are specific to the chip
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("SPI transfer failed, {0}")]
+    Bus(#[from] io::Error),
+    #[error(transparent)]
+    MAX6675(#[from] FrameError),
+    #[error(transparent)]
+    MAX31855(#[from] max31855::FrameError),
+    #[error(transparent)]
+    MAX31856(#[from] max31856::FrameError),
+}
+
+impl Error {
+    /// Short name of the fault, usable as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Bus(_) => "bus",
+            Self::MAX6675(err) => err.kind(),
+            Self::MAX31855(err) => err.kind(),
+            Self::MAX31856(err) => err.kind(),
+        }
+    }
+}
+
+/// Thermocouple to digital converter on the SPI bus
+pub trait Converter: Send {
+    fn chip(&self) -> Chip;
+    fn read(&mut self) -> Result<Reading, Error>;
+}
+
+/// Converter together with the configuration of its channel
+pub struct Sensor {
+    pub channel: Channel,
+    converter: Box<dyn Converter>,
+}
+
+impl Sensor {
+    pub fn name(&self) -> &str {
+        &self.channel.name
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.converter.chip()
+    }
+
+    pub fn read(&mut self) -> Result<Reading, Error> {
+        self.converter.read()
+    }
+}
+
+/// Health of the sensor, kept even when the sensor can not be read
+#[derive(Default)]
+pub struct Status {
+    pub up: bool,
+    /// Number of failed reads per fault kind
+    pub errors: BTreeMap<&'static str, u64>,
+    pub last_read: Option<SystemTime>,
+}
+
+impl Status {
+    pub fn record_success(&mut self, acquired: SystemTime) {
+        self.up = true;
+        self.last_read = Some(acquired);
+    }
+
+    pub fn record_error(&mut self, err: &Error) {
+        self.up = false;
+        *self.errors.entry(err.kind()).or_default() += 1;
+    }
 }
 
 pub struct Temperatures {
-    pub inner: BTreeMap<usize, f64>,
-    pub filtered: BTreeMap<usize, Kalman>,
-    pub calibration: BTreeMap<usize, f64>,
+    pub channels: Vec<Channel>,
+    pub inner: BTreeMap<String, Timestamped>,
+    pub filtered: BTreeMap<String, Kalman>,
+    pub calibration: BTreeMap<String, f64>,
+    pub cold_junction: BTreeMap<String, Timestamped>,
+    pub status: BTreeMap<String, Status>,
+    pub stale_after: Duration,
 }
 
 impl Temperatures {
     const DEFAULT_OFFSET: f64 = 0.0;
 
-    pub fn new(num_sensors: usize, kalman_descriptor: &kalman::Descriptor) -> Self {
+    pub fn new(descriptor: &Descriptor, kalman_descriptor: &kalman::Descriptor) -> Self {
         let mut default_calibration = BTreeMap::new();
         let mut filtered = BTreeMap::new();
-        for sensor_id in 0..num_sensors {
+        let mut status = BTreeMap::new();
+        for channel in &descriptor.channels {
             // Default calibration offset is 0.0 ˚C
-            default_calibration.insert(sensor_id, Self::DEFAULT_OFFSET);
-            filtered.insert(sensor_id, Kalman::new(kalman_descriptor));
+            let offset = channel.offset.unwrap_or(Self::DEFAULT_OFFSET);
+            default_calibration.insert(channel.name.clone(), offset);
+            filtered.insert(channel.name.clone(), Kalman::new(kalman_descriptor));
+            status.insert(channel.name.clone(), Status::default());
         }
 
         Self {
+            channels: descriptor.channels.clone(),
             inner: BTreeMap::new(),
             filtered,
             calibration: default_calibration,
+            cold_junction: BTreeMap::new(),
+            status,
+            stale_after: descriptor.polling.stale_after(),
         }
     }
 
-    pub fn load_calibration(&mut self, path: &str) -> Result<()> {
-        let calibration_file = File::open(path).context("Failed to open calibration file")?;
+    /// The sensor was not read for too long, its values are not current anymore
+    pub fn is_stale(&self, name: &str) -> bool {
+        self.status
+            .get(name)
+            .and_then(|status| status.last_read)
+            .is_some_and(|last_read| is_stale(last_read, self.stale_after))
+    }
+
+    /// Last raw temperature of the sensor unless it is stale
+    pub fn current_raw(&self, name: &str) -> Option<f64> {
+        self.current(&self.inner, name)
+    }
+
+    /// Last cold-junction temperature of the sensor unless it is stale
+    pub fn current_cold_junction(&self, name: &str) -> Option<f64> {
+        self.current(&self.cold_junction, name)
+    }
+
+    fn current(&self, readings: &BTreeMap<String, Timestamped>, name: &str) -> Option<f64> {
+        readings
+            .get(name)
+            .filter(|reading| !is_stale(reading.acquired, self.stale_after))
+            .map(|reading| reading.value)
+    }
+
+    /// Applies offsets from the calibration file to the sensors without an offset in config
+    pub fn load_calibration(&mut self, descriptor: &Descriptor) -> Result<()> {
+        let calibration_file =
+            File::open(&descriptor.calibration_file).context("Failed to open calibration file")?;
         let reader = BufReader::new(calibration_file);
 
-        self.calibration =
+        let mut calibration: BTreeMap<String, f64> =
             serde_json::from_reader(reader).context("Failed to parse calibration file")?;
+        for channel in &descriptor.channels {
+            match (channel.offset, calibration.remove(&channel.name)) {
+                (None, Some(offset)) => {
+                    self.calibration.insert(channel.name.clone(), offset);
+                }
+                (Some(_), Some(_)) => {
+                    debug!(
+                        "Calibration of sensor {} is overridden by config",
+                        channel.name
+                    )
+                }
+                _ => {}
+            }
+        }
+        for name in calibration.keys() {
+            warn!("Calibration of unknown sensor {name} is ignored");
+        }
         Ok(())
     }
 }
 
-pub struct MAX6675 {
-    spi: Arc<Mutex<Spi>>,
-    cs: OutputPin,
-    pub id: usize,
+/// Raw 16-bit frame clocked out of the MAX6675
+///
+/// D15 dummy sign bit, always low
+/// D14 - D3 12-bit temperature reading, 0.25 ˚C per LSB
+/// D2 high when the thermocouple input is open
+/// D1 device ID, always low
+/// D0 three-state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame(u16);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FrameError {
+    /// If the sensor is mounted in wrong way, it can hold MISO high
+    #[error("Sensor connection is probably wrong, frame 0x{0:04X}")]
+    BusStuckHigh(u16),
+    /// If there are no data on the bus, there are still SCK present,
+    /// therefore 0x0 is read
+    #[error("Sensor is probably not present, frame 0x{0:04X}")]
+    NotPresent(u16),
+    #[error("Dummy sign bit D15 is set, frame 0x{0:04X}")]
+    SignBitSet(u16),
+    #[error("Device ID bit D1 is set, frame 0x{0:04X}")]
+    DeviceIdBitSet(u16),
+    #[error("Thermocouple is probably not connected, frame 0x{0:04X}")]
+    OpenThermocouple(u16),
 }
 
-impl MAX6675 {
-    pub fn new(spi: Arc<Mutex<Spi>>, cs_pin: usize, id: usize) -> Self {
-        let mut cs = Gpio::new()
-            .expect("Failed to create new GPIO pin instance")
-            .get(cs_pin as u8)
-            .expect("Failed to get pin")
-            .into_output();
+impl FrameError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::BusStuckHigh(_) => "bus_stuck_high",
+            Self::NotPresent(_) => "not_present",
+            Self::SignBitSet(_) => "sign_bit",
+            Self::DeviceIdBitSet(_) => "device_id",
+            Self::OpenThermocouple(_) => "open_thermocouple",
+        }
+    }
+}
 
-        cs.set_high();
+impl Frame {
+    const SIGN_BIT: u16 = 0x8000;
+    const OPEN_THERMOCOUPLE_BIT: u16 = 0x0004;
+    const DEVICE_ID_BIT: u16 = 0x0002;
+    const TEMPERATURE_SHIFT: u16 = 3;
+    const TEMPERATURE_MASK: u16 = 0x0FFF;
+    const RESOLUTION: f64 = 0.25;
 
-        Self { spi, cs, id }
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        Self(u16::from_be_bytes(bytes))
     }
 
-    pub fn read_temp(&mut self) -> Result<f64> {
-        let mut spi = self.spi.lock().expect("BUG: Failed to acquire SPI device");
+    pub fn sign_bit(&self) -> bool {
+        self.0 & Self::SIGN_BIT != 0
+    }
+
+    pub fn open_thermocouple(&self) -> bool {
+        self.0 & Self::OPEN_THERMOCOUPLE_BIT != 0
+    }
+
+    pub fn device_id(&self) -> bool {
+        self.0 & Self::DEVICE_ID_BIT != 0
+    }
 
-        // Custom CS implementation
-        self.cs.set_low();
-        let value = spi.read()?;
-        self.cs.set_high();
+    /// Unsigned 12-bit reading, the MAX6675 can not measure below 0 ˚C
+    pub fn raw_temperature(&self) -> u16 {
+        (self.0 >> Self::TEMPERATURE_SHIFT) & Self::TEMPERATURE_MASK
+    }
 
-        let mut value: usize = (value[0] as usize) << 8 | value[1] as usize;
+    pub fn temperature(&self) -> f64 {
+        self.raw_temperature() as f64 * Self::RESOLUTION
+    }
 
-        // If the sensor is mountend in wrong way, it can hold MISO high.
-        if value == 0xFFFF_FFFF {
-            return Err(anyhow!(
-                "Sensor connection is probaly wrong, value 0x{value:08X}"
-            ));
+    /// Checks all the fault indications and returns the temperature in ˚C
+    pub fn decode(&self) -> Result<f64, FrameError> {
+        match self.0 {
+            0xFFFF => return Err(FrameError::BusStuckHigh(self.0)),
+            0x0000 => return Err(FrameError::NotPresent(self.0)),
+            _ => {}
+        }
+        if self.sign_bit() {
+            return Err(FrameError::SignBitSet(self.0));
         }
-        // If there are no data on the bus, there are still SCK present,
-        // therefore 0x0 is read.
-        if value == 0x0000_0000 {
-            return Err(anyhow!(
-                "Sensor is probaly not present, value 0x{value:08X}"
-            ));
+        if self.device_id() {
+            return Err(FrameError::DeviceIdBitSet(self.0));
         }
-        // Bit D2 indicates should be normally low.
-        if value & 0x4 == 0x4 {
-            return Err(anyhow!(
-                "Thermocouple is probably not connected, value 0x{value:08X}"
-            ));
+        if self.open_thermocouple() {
+            return Err(FrameError::OpenThermocouple(self.0));
         }
 
-        // First 3 bits are just status flags
-        value >>= 3;
-        // Negative value, take 2's compliment. Compute this with subtraction.
-        if value & 0x10000000 == 0x10000000 {
-            value -= 4096
+        Ok(self.temperature())
+    }
+}
+
+pub struct MAX6675 {
+    spi: SpiDevice,
+}
+
+impl MAX6675 {
+    const NUM_RESPONSE_BYTES: usize = 2;
+
+    pub fn new(spi: SpiDevice) -> Self {
+        Self { spi }
+    }
+
+    pub fn read_temp(&mut self) -> Result<f64, Error> {
+        let mut value = [0_u8; Self::NUM_RESPONSE_BYTES];
+        self.spi.read(&mut value)?;
+
+        Ok(Frame::from_bytes(value).decode()?)
+    }
+}
+
+impl Converter for MAX6675 {
+    fn chip(&self) -> Chip {
+        Chip::MAX6675
+    }
+
+    fn read(&mut self) -> Result<Reading, Error> {
+        Ok(Reading {
+            temperature: self.read_temp()?,
+            cold_junction: None,
+        })
+    }
+}
+
+/// Opens the SPI bus and creates a sensor for every configured channel
+pub fn open_sensors(descriptor: &Descriptor) -> Result<Vec<Sensor>> {
+    let spi: SharedBus = Arc::new(Mutex::new(
+        Spi::open().context("Failed to open SPI device")?,
+    ));
+    descriptor
+        .channels
+        .iter()
+        .map(|channel| {
+            let cs = GpioChipSelect::new(channel.cs_pin)
+                .with_context(|| format!("Failed to get CS pin {}", channel.cs_pin))?;
+            let cs: Box<dyn ChipSelect> = Box::new(cs);
+            let mode = channel.chip.spi_mode();
+            Ok(channel.create(SpiDevice::new(spi.clone(), cs, mode)))
+        })
+        .collect()
+}
+
+/// Opens the real sensors, or the simulated ones when the simulation is given
+pub fn connect_sensors(
+    descriptor: &Descriptor,
+    simulation: Option<&simulation::Descriptor>,
+) -> Result<Vec<Sensor>> {
+    match simulation {
+        Some(simulation) => Ok(simulate_sensors(descriptor, simulation)),
+        None => open_sensors(descriptor),
+    }
+}
+
+/// Creates sensors on a simulated SPI bus, every thermocouple follows the waveform
+pub fn simulate_sensors(
+    descriptor: &Descriptor,
+    simulation: &simulation::Descriptor,
+) -> Vec<Sensor> {
+    let bus = SimulatedBus::new();
+    let spi: SharedBus = Arc::new(Mutex::new(bus.clone()));
+    descriptor
+        .channels
+        .iter()
+        .map(|channel| {
+            let pin = channel.cs_pin;
+            let thermocouple = &simulation.sensors;
+            // Cold junction sits at the ambient temperature
+            let cold_junction = &simulation.ambient_temperature;
+            match channel.chip {
+                Chip::MAX6675 => bus.attach(pin, simulation::MAX6675::new(thermocouple)),
+                Chip::MAX31855 => {
+                    bus.attach(pin, simulation::MAX31855::new(thermocouple, cold_junction))
+                }
+                Chip::MAX31856 => {
+                    bus.attach(pin, simulation::MAX31856::new(thermocouple, cold_junction))
+                }
+            }
+            let cs = Box::new(bus.chip_select(pin));
+            channel.create(SpiDevice::new(spi.clone(), cs, channel.chip.spi_mode()))
+        })
+        .collect()
+}
+
+struct Metrics {
+    temperature: Gauge,
+    filtered: Gauge,
+    raw: Gauge,
+    cold_junction: Gauge,
+    up: Gauge,
+    read_errors: Counter,
+    last_read: Gauge,
+}
+
+impl Metrics {
+    /// Temperatures are not exported when the sensors are not read for longer than `stale_after`
+    fn new(registry: &Registry, stale_after: Duration) -> Self {
+        Self {
+            temperature: registry
+                .gauge(
+                    "max6675_temperature_c",
+                    "Calibrated thermocouple temperature in ˚C",
+                )
+                .stale_after(stale_after),
+            filtered: registry
+                .gauge(
+                    "max6675_temperature_filtered_c",
+                    "Calibrated and Kalman filtered thermocouple temperature in ˚C",
+                )
+                .stale_after(stale_after),
+            raw: registry
+                .gauge(
+                    "max6675_temperature_raw_c",
+                    "Thermocouple temperature as read from the chip in ˚C",
+                )
+                .stale_after(stale_after),
+            cold_junction: registry
+                .gauge(
+                    "max6675_cold_junction_c",
+                    "Cold-junction temperature of the chip in ˚C",
+                )
+                .stale_after(stale_after),
+            up: registry.gauge(
+                "max6675_sensor_up",
+                "Whether the last read of the sensor succeeded",
+            ),
+            read_errors: registry.counter(
+                "max6675_read_errors_total",
+                "Number of failed sensor reads by the fault kind",
+            ),
+            last_read: registry.gauge(
+                "max6675_last_read_timestamp_seconds",
+                "Unix time of the last successful sensor read",
+            ),
         }
+    }
 
-        Ok(value as f64 * 0.25)
+    fn update(&self, labels: &[(&str, &str)], status: &Status, error: Option<&Error>) {
+        self.up.set(labels, status.up as u8 as f64);
+        if let Some(err) = error {
+            let mut error_labels = labels.to_vec();
+            error_labels.push(("kind", err.kind()));
+            self.read_errors.inc(&error_labels);
+            self.temperature.remove(labels);
+            self.filtered.remove(labels);
+            self.raw.remove(labels);
+            self.cold_junction.remove(labels);
+        }
+        if let Some(last_read) = status.last_read {
+            self.last_read.set(labels, unix_seconds(last_read));
+        }
     }
 }
 
+/// Reads all the sensors, the SPI bus is retried until it opens
 pub async fn update_temp_periodically(
     descriptor: Descriptor,
+    simulation: Option<simulation::Descriptor>,
     temperatures: Arc<Mutex<Temperatures>>,
+    mut ticker: Ticker,
+    context: TaskContext,
 ) {
-    const UPDATE_PERIOD_MS: Duration = Duration::from_millis(400);
-
-    let spi = Arc::new(Mutex::new(Spi::open()));
-    let mut sensors = Vec::new();
-    for (id, cs_pin) in descriptor.cs_pins.iter().enumerate() {
-        sensors.push(MAX6675::new(spi.clone(), *cs_pin, id));
-    }
+    let TaskContext {
+        registry,
+        samples,
+        health,
+    } = context;
+    let stale_after = temperatures
+        .lock()
+        .expect("BUG: Failed to acquire temperatures lock")
+        .stale_after;
+    let metrics = Metrics::new(&registry, stale_after);
+    let mut sensors = health
+        .connect("max6675", || async {
+            connect_sensors(&descriptor, simulation.as_ref())
+        })
+        .await;
 
     loop {
+        ticker.tick().await;
         {
             let mut temperatures = temperatures
                 .lock()
                 .expect("BUG: Failed to acquire temperatures lock");
 
             temperatures.inner.clear();
+            temperatures.cold_junction.clear();
+            let mut error = None;
             for sensor in sensors.iter_mut() {
-                let temp = match sensor.read_temp() {
-                    Ok(temp) => temp,
-                    _ => {
-                        warn!("Failed to read sensor_id {}", sensor.id);
+                let acquired = SystemTime::now();
+                let result = sensor.read();
+                let name = sensor.name().to_owned();
+                let labels = sensor.channel.labels();
+                let status = temperatures.status.entry(name.clone()).or_default();
+                let reading = match result {
+                    Ok(reading) => {
+                        status.record_success(acquired);
+                        metrics.update(&labels, status, None);
+                        reading
+                    }
+                    Err(err) => {
+                        status.record_error(&err);
+                        metrics.update(&labels, status, Some(&err));
+                        warn!("Failed to read {:?} sensor {name}, {err}", sensor.chip());
+                        samples.publish(format!("sensors/{name}/raw"), "˚C", None, acquired);
+                        samples.publish(format!("sensors/{name}/calibrated"), "˚C", None, acquired);
+                        error = Some(err);
                         continue;
                     }
                 };
 
-                temperatures.inner.insert(sensor.id, temp);
+                temperatures.inner.insert(
+                    name.clone(),
+                    Timestamped::new(reading.temperature, acquired),
+                );
+                metrics.raw.set_at(&labels, reading.temperature, acquired);
+                samples.publish(
+                    format!("sensors/{name}/raw"),
+                    "˚C",
+                    Some(reading.temperature),
+                    acquired,
+                );
+                if let Some(cold_junction) = reading.cold_junction {
+                    temperatures
+                        .cold_junction
+                        .insert(name.clone(), Timestamped::new(cold_junction, acquired));
+                    metrics
+                        .cold_junction
+                        .set_at(&labels, cold_junction, acquired);
+                    samples.publish(
+                        format!("sensors/{name}/cold_junction"),
+                        "˚C",
+                        Some(cold_junction),
+                        acquired,
+                    );
+                }
 
-                if let Some(filtered_temperature) = temperatures.filtered.get_mut(&sensor.id) {
-                    filtered_temperature.update(temp);
+                let calibration_offset = temperatures.calibration.get(&name).copied();
+                if let Some(filtered_temperature) = temperatures.filtered.get_mut(&name) {
+                    filtered_temperature.update(reading.temperature);
+                    if let Some(calibration_offset) = calibration_offset {
+                        let filtered = filtered_temperature.value() + calibration_offset;
+                        metrics.filtered.set_at(&labels, filtered, acquired);
+                        samples.publish(
+                            format!("sensors/{name}/filtered"),
+                            "˚C",
+                            Some(filtered),
+                            acquired,
+                        );
+                    }
+                }
+                if let Some(calibration_offset) = calibration_offset {
+                    let calibrated = reading.temperature + calibration_offset;
+                    metrics.temperature.set_at(&labels, calibrated, acquired);
+                    samples.publish(
+                        format!("sensors/{name}/calibrated"),
+                        "˚C",
+                        Some(calibrated),
+                        acquired,
+                    );
                 }
             }
+            // Single broken sensor is in its own status, the backend fails when none can be read
+            health.report(
+                "max6675",
+                error.as_ref().filter(|_| temperatures.inner.is_empty()),
+            );
         }
-        sleep(UPDATE_PERIOD_MS).await;
     }
 }
 
 pub async fn calibrate_sensors(
+    mut sensors: Vec<Sensor>,
     descriptor: Descriptor,
     real_temp: f64,
     kalman_descriptor: &kalman::Descriptor,
 ) -> Result<()> {
     const NUM_MEASUREMENTS: usize = 100;
-    // Minimal delay between measurements is 220 ms
+    // Minimal delay between measurements is the MAX6675 conversion time 220 ms
     const MEAS_DELAY_MS: Duration = Duration::from_millis(330);
 
-    let mut temperatures: BTreeMap<usize, Vec<f64>> = BTreeMap::new();
-
-    let spi = Arc::new(Mutex::new(Spi::open()));
-    let mut sensors = Vec::new();
-    for (id, cs_pin) in descriptor.cs_pins.iter().enumerate() {
-        sensors.push(MAX6675::new(spi.clone(), *cs_pin, id));
-        temperatures.insert(id, Vec::new());
-    }
-
     let mut kalman_descriptor = kalman_descriptor.clone();
     kalman_descriptor.initial_temperature = real_temp;
     let mut filters = BTreeMap::new();
     for sensor in &sensors {
-        filters.insert(sensor.id, Kalman::new(&kalman_descriptor));
+        filters.insert(sensor.name().to_owned(), Kalman::new(&kalman_descriptor));
+        if sensor.channel.offset.is_some() {
+            warn!(
+                "Sensor {} has offset in config, the calibration will not be used",
+                sensor.name()
+            );
+        }
     }
 
     info!(
@@ -182,30 +701,31 @@ pub async fn calibrate_sensors(
     );
     for _ in 0..NUM_MEASUREMENTS {
         for sensor in sensors.iter_mut() {
-            if let Ok(temp) = sensor.read_temp() {
-                let filter = filters
-                    .get_mut(&sensor.id)
-                    .expect("BUG: Failed to get filter");
-                filter.update(temp);
-            } else {
-                warn!("Failed to read temp from sensor {}", sensor.id);
+            match sensor.read() {
+                Ok(reading) => {
+                    let filter = filters
+                        .get_mut(sensor.name())
+                        .expect("BUG: Failed to get filter");
+                    filter.update(reading.temperature);
+                }
+                Err(err) => warn!("Failed to read temp from sensor {}, {err}", sensor.name()),
             }
         }
         sleep(MEAS_DELAY_MS).await;
     }
 
     let mut calibration = BTreeMap::new();
-    for (sensor_id, filter) in filters {
+    for (name, filter) in filters {
         let offset = real_temp - filter.value();
-        debug!("sensor_id {sensor_id:2}, offset {offset:+5.2}");
-        calibration.insert(sensor_id, offset);
+        debug!("sensor {name:>8}, offset {offset:+5.2}");
+        calibration.insert(name, offset);
     }
     store_calibration(calibration, &descriptor.calibration_file)
         .context("BUG: Failed to store calibration")?;
     Ok(())
 }
 
-pub fn store_calibration(calibration: BTreeMap<usize, f64>, path: &str) -> Result<()> {
+pub fn store_calibration(calibration: BTreeMap<String, f64>, path: &str) -> Result<()> {
     let mut calibration_file = File::create(path).context("Failed to open calibration file")?;
     calibration_file
         .write_all(
@@ -216,3 +736,85 @@ pub fn store_calibration(calibration: BTreeMap<usize, f64>, path: &str) -> Resul
         .context("BUG: Failed to write calibration file")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spi::Script;
+
+    #[test]
+    fn decodes_temperature() {
+        assert_eq!(Frame(0x0008).decode(), Ok(0.25));
+        assert_eq!(Frame(0x00C8).decode(), Ok(6.25));
+        assert_eq!(Frame(0x0C80).decode(), Ok(100.0));
+        assert_eq!(Frame(0x7FF8).decode(), Ok(1023.75));
+    }
+
+    #[test]
+    fn ignores_three_state_bit() {
+        assert_eq!(Frame(0x0C81).decode(), Ok(100.0));
+    }
+
+    #[test]
+    fn exposes_status_bits() {
+        let frame = Frame(0x8C86);
+        assert!(frame.sign_bit());
+        assert!(frame.open_thermocouple());
+        assert!(frame.device_id());
+        assert_eq!(frame.raw_temperature(), 400);
+
+        let frame = Frame(0x0C80);
+        assert!(!frame.sign_bit());
+        assert!(!frame.open_thermocouple());
+        assert!(!frame.device_id());
+    }
+
+    #[test]
+    fn detects_bus_faults() {
+        assert_eq!(
+            Frame(0xFFFF).decode(),
+            Err(FrameError::BusStuckHigh(0xFFFF))
+        );
+        assert_eq!(Frame(0x0000).decode(), Err(FrameError::NotPresent(0x0000)));
+    }
+
+    #[test]
+    fn detects_sign_bit() {
+        assert_eq!(Frame(0x8C80).decode(), Err(FrameError::SignBitSet(0x8C80)));
+        // Sign bit takes precedence over other faults
+        assert_eq!(Frame(0x8006).decode(), Err(FrameError::SignBitSet(0x8006)));
+    }
+
+    #[test]
+    fn detects_device_id_bit() {
+        assert_eq!(
+            Frame(0x0C82).decode(),
+            Err(FrameError::DeviceIdBitSet(0x0C82))
+        );
+    }
+
+    #[test]
+    fn detects_open_thermocouple() {
+        assert_eq!(
+            Frame(0x0004).decode(),
+            Err(FrameError::OpenThermocouple(0x0004))
+        );
+        assert_eq!(
+            Frame(0x0C84).decode(),
+            Err(FrameError::OpenThermocouple(0x0C84))
+        );
+    }
+
+    #[test]
+    fn reads_frames_from_simulated_bus() {
+        let bus = SimulatedBus::new();
+        bus.attach(14, Script::new([0x0C80, 0x0C84, 0x0000]));
+        let spi: SharedBus = Arc::new(Mutex::new(bus.clone()));
+        let cs = Box::new(bus.chip_select(14));
+        let mut sensor = MAX6675::new(SpiDevice::new(spi, cs, Chip::MAX6675.spi_mode()));
+
+        assert_eq!(sensor.read_temp().unwrap(), 100.0);
+        assert!(sensor.read_temp().is_err());
+        assert!(sensor.read_temp().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::max6675::Temperatures;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Timing of the periodic reads, flattened into the section of every backend,
+/// the default poll interval is given by the backend
+#[derive(Clone, Copy, Deserialize, Serialize, Debug)]
+pub struct Polling<const DEFAULT_MS: u64 = 400> {
+    /// Period of reading the backend
+    #[serde(default = "default_poll_interval_ms::<DEFAULT_MS>")]
+    pub poll_interval_ms: u64,
+    /// Readings older than this are stale and not exported, 5 poll intervals by default
+    pub stale_after_ms: Option<u64>,
+}
+
+fn default_poll_interval_ms<const MS: u64>() -> u64 {
+    MS
+}
+
+impl<const DEFAULT_MS: u64> Polling<DEFAULT_MS> {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    /// Staleness threshold of the backend, the configured one or a few poll intervals
+    pub fn stale_after(&self) -> Duration {
+        const POLL_INTERVALS: u32 = 5;
+        self.stale_after_ms
+            .map(Duration::from_millis)
+            .unwrap_or(self.poll_interval() * POLL_INTERVALS)
+    }
+
+    pub fn validate(&self, section: &str) -> Result<()> {
+        if self.poll_interval_ms == 0 {
+            bail!("Poll interval of [{section}] must be longer than 0 ms");
+        }
+        // Values would be stale before the next read
+        if self.stale_after() <= self.poll_interval() {
+            bail!("Staleness threshold of [{section}] must be longer than the poll interval");
+        }
+        Ok(())
+    }
+}
+
+/// Whether the value acquired at the time is too old to be shown as current
+pub fn is_stale(acquired: SystemTime, stale_after: Duration) -> bool {
+    // Clock going backwards does not make the value stale
+    acquired.elapsed().is_ok_and(|age| age > stale_after)
+}
+
+/// Value with the time it was acquired at
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamped {
+    pub value: f64,
+    pub acquired: SystemTime,
+}
+
+impl Timestamped {
+    pub fn new(value: f64, acquired: SystemTime) -> Self {
+        Self { value, acquired }
+    }
+}
+
+/// Last reading of a single value source, the value is cleared when the read fails
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub value: Option<f64>,
+    pub up: bool,
+    pub last_read: Option<SystemTime>,
+    pub stale_after: Duration,
+}
+
+impl Measurement {
+    pub fn new(stale_after: Duration) -> Self {
+        Self {
+            value: None,
+            up: false,
+            last_read: None,
+            stale_after,
+        }
+    }
+
+    /// The task did not read the value for too long, e.g. it hangs waiting for a reply
+    pub fn is_stale(&self) -> bool {
+        self.last_read
+            .is_some_and(|last_read| is_stale(last_read, self.stale_after))
+    }
+
+    /// Last value unless it is stale
+    pub fn current(&self) -> Option<f64> {
+        self.value.filter(|_| !self.is_stale())
+    }
+
+    /// Stores the reading with the time it was acquired at
+    pub fn update(&mut self, reading: Option<f64>, acquired: SystemTime) {
+        self.value = reading;
+        self.up = reading.is_some();
+        if reading.is_some() {
+            self.last_read = Some(acquired);
+        }
+    }
+}
+
+/// State shared between the periodic tasks and the web server
+pub struct Measurements {
+    pub temperatures: Arc<Mutex<Temperatures>>,
+    pub ambient_temperature: Arc<Mutex<Measurement>>,
+    /// Measurements of every instrument by the instrument and the measurement name
+    pub instruments: BTreeMap<String, BTreeMap<String, Arc<Mutex<Measurement>>>>,
+    pub power: Arc<Mutex<Measurement>>,
+}
+
+/// Single value of a series pushed to the live stream, `None` when the read failed
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    /// Source of the value, e.g. `sensors/tc0/raw` or `scope/psu_voltage`
+    pub series: String,
+    pub unit: &'static str,
+    pub value: Option<f64>,
+    /// Unix time of the acquisition, not of the publishing
+    pub timestamp: f64,
+    /// Test run active when the value was read
+    pub run: Option<u64>,
+}
+
+/// Every periodic task publishes its samples here, every stream client subscribes
+#[derive(Clone)]
+pub struct Samples {
+    sender: broadcast::Sender<Sample>,
+    run: Arc<Mutex<Option<u64>>>,
+}
+
+impl Samples {
+    /// Samples kept for the slow subscribers, older ones are skipped
+    const CAPACITY: usize = 1024;
+
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(Self::CAPACITY);
+        Self {
+            sender,
+            run: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn publish(
+        &self,
+        series: impl Into<String>,
+        unit: &'static str,
+        value: Option<f64>,
+        acquired: SystemTime,
+    ) {
+        let sample = Sample {
+            series: series.into(),
+            unit,
+            value,
+            timestamp: unix_seconds(acquired),
+            run: *self.run.lock().expect("BUG: Failed to acquire run lock"),
+        };
+        // Sending fails only if nobody is subscribed, the sample is not needed then
+        let _ = self.sender.send(sample);
+    }
+
+    /// Tags all the following samples with the run
+    pub fn set_run(&self, run: Option<u64>) {
+        *self.run.lock().expect("BUG: Failed to acquire run lock") = run;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Sample> {
+        self.sender.subscribe()
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .expect("BUG: Failed to get unix time")
+        .as_secs_f64()
+}
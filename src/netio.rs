@@ -1,52 +1,198 @@
-use anyhow::{anyhow, Context, Result};
-use serde::Deserialize;
+use crate::measurement::{Measurement, Polling};
+use crate::scheduler::{TaskContext, Ticker};
+use crate::simulation::{self, Signal, Waveform};
+use anyhow::bail;
+use log::warn;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::{Arc, Mutex};
-use tokio::time::{sleep, Duration};
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::time::{Duration, Instant};
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Descriptor {
     address: String,
     output: Vec<String>,
+    #[serde(flatten)]
+    pub polling: Polling<500>,
+}
+
+impl Descriptor {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for output in &self.output {
+            if output_id(output).is_none() {
+                bail!("Netio output {output} is not valid, outputs are named e.g. out3");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outputs are configured by name, e.g. "out3" is the output with ID 3
+fn output_id(name: &str) -> Option<u64> {
+    name.strip_prefix("out")?.parse().ok()
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("HTTP request failed, {0}")]
+    Http(reqwest::Error),
+    #[error("HTTP request timed out")]
+    Timeout,
+    #[error("HTTP request failed with status {0}")]
+    Status(StatusCode),
+    #[error("Failed to parse the response, {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Output {0} is missing in the response")]
+    MissingOutput(u64),
+    #[error("Load of output {0} is missing in the response")]
+    MissingLoad(u64),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Http(err)
+        }
+    }
+}
+
+impl Error {
+    /// Short name of the fault, usable as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Http(_) => "http",
+            Self::Timeout => "timeout",
+            Self::Status(_) => "status",
+            Self::Parse(_) => "parse",
+            Self::MissingOutput(_) => "missing_output",
+            Self::MissingLoad(_) => "missing_load",
+        }
+    }
+}
+
+enum Source {
+    Http { client: Client, url: String },
+    Simulated(Signal),
 }
 
 pub struct Netio {
-    url: String,
+    source: Source,
+    outputs: Vec<u64>,
 }
 
 impl Netio {
+    /// Unresponsive Netio must not hold the polling loop forever
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
     pub fn new(descriptor: &Descriptor) -> Self {
+        let client = Client::builder()
+            .timeout(Self::REQUEST_TIMEOUT)
+            .build()
+            .expect("BUG: Failed to build the HTTP client");
         let url = format!("http://{}/netio.json", descriptor.address);
-        Self { url }
+        Self {
+            source: Source::Http { client, url },
+            outputs: Self::output_ids(descriptor),
+        }
+    }
+
+    pub fn simulated(descriptor: &Descriptor, waveform: &Waveform) -> Self {
+        Self {
+            source: Source::Simulated(Signal::new(waveform)),
+            outputs: Self::output_ids(descriptor),
+        }
+    }
+
+    fn output_ids(descriptor: &Descriptor) -> Vec<u64> {
+        descriptor
+            .output
+            .iter()
+            .map(|output| output_id(output).expect("BUG: Netio outputs are not validated"))
+            .collect()
     }
 
-    pub async fn read_power(&self) -> Result<f64> {
-        let response = reqwest::get(&self.url).await?.text().await?;
+    pub async fn read_power(&mut self) -> Result<f64, Error> {
+        let response = match &mut self.source {
+            Source::Http { client, url } => {
+                let response = client.get(url.as_str()).send().await?;
+                if !response.status().is_success() {
+                    return Err(Error::Status(response.status()));
+                }
+                response.text().await?
+            }
+            Source::Simulated(signal) => simulation::netio_json(&self.outputs, signal.sample()),
+        };
         let v: Value = serde_json::from_str(&response)?;
 
         let mut load = 0.0;
-        load += v["Outputs"][2]["Load"]
-            .as_f64()
-            .ok_or(anyhow!("Failed to get load"))?;
-        load += v["Outputs"][3]["Load"]
-            .as_f64()
-            .ok_or(anyhow!("Failed to get load"))?;
+        for id in &self.outputs {
+            let output = v["Outputs"]
+                .as_array()
+                .and_then(|outputs| outputs.iter().find(|output| output["ID"] == *id))
+                .ok_or(Error::MissingOutput(*id))?;
+            load += output["Load"].as_f64().ok_or(Error::MissingLoad(*id))?;
+        }
 
         Ok(load)
     }
 }
 
-pub async fn update_power_periodically(descriptor: Descriptor, power: Arc<Mutex<Option<f64>>>) {
-    const UPDATE_PERIOD_MS: Duration = Duration::from_millis(500);
-
-    let netio = Netio::new(&descriptor);
+pub async fn update_power_periodically(
+    mut netio: Netio,
+    power: Arc<Mutex<Measurement>>,
+    mut ticker: Ticker,
+    context: TaskContext,
+) {
+    let TaskContext {
+        registry,
+        samples,
+        health,
+    } = context;
+    let stale_after = power
+        .lock()
+        .expect("BUG: Failed to acquire power lock")
+        .stale_after;
+    let power_gauge = registry
+        .gauge(
+            "netio_power_w",
+            "Power consumption of the configured Netio outputs in W",
+        )
+        .stale_after(stale_after);
+    let request_duration = registry.histogram(
+        "netio_request_duration_seconds",
+        "Time to get the Netio JSON response",
+        &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+    );
+    let request_errors = registry.counter(
+        "netio_request_errors_total",
+        "Number of failed Netio requests by the fault kind",
+    );
 
     loop {
-        let power_reading = netio.read_power().await.ok();
-        {
-            let mut power = power.lock().expect("BUG: Failed to acquire voltagelock");
-            *power = power_reading;
-        }
-        sleep(UPDATE_PERIOD_MS).await;
+        ticker.tick().await;
+        let start = Instant::now();
+        let acquired = SystemTime::now();
+        let result = netio.read_power().await;
+        health.report("netio", result.as_ref().err());
+        let power_reading = match result {
+            Ok(power) => Some(power),
+            Err(err) => {
+                warn!("Failed to read power, {err}");
+                request_errors.inc(&[("kind", err.kind())]);
+                None
+            }
+        };
+        power
+            .lock()
+            .expect("BUG: Failed to acquire power lock")
+            .update(power_reading, acquired);
+        request_duration.observe(&[], start.elapsed().as_secs_f64());
+        power_gauge.set_or_remove_at(&[], power_reading, acquired);
+        samples.publish("netio/power", "W", power_reading, acquired);
     }
 }
@@ -0,0 +1,398 @@
+//! Recording of the samples into rotating files
+//!
+//! Every session gets its own directory with files of every configured format,
+//! a new file is started when the current one exceeds `max_file_bytes`.
+//!
+//! CSV has the header `timestamp,series,unit,value,run`,
+//! failed reads have an empty value, samples outside of any test run have an empty run,
+//! the fields with commas, quotes or line breaks are quoted.
+//!
+//! Binary file starts with the magic `MAXREC` followed by the version bytes `0x01 0x00`,
+//! then the records follow, all numbers are little endian
+//! `0x01` series definition, u16 series ID, u16 name length, name, u16 unit length, unit
+//! `0x02` sample, u16 series ID, f64 unix timestamp, f32 value, NaN when the read failed,
+//! u32 test run ID, 0 outside of any test run
+
+use crate::measurement::{unix_seconds, Sample, Samples};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct Descriptor {
+    /// Every session is stored in its own subdirectory
+    pub directory: String,
+    /// Size in bytes when a new file is started
+    pub max_file_bytes: u64,
+    pub formats: Vec<Format>,
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Self {
+            directory: "recordings".to_owned(),
+            max_file_bytes: 64 * 1024 * 1024,
+            formats: vec![Format::Csv, Format::Binary],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Binary,
+}
+
+impl Format {
+    const BINARY_MAGIC: &'static [u8] = b"MAXREC\x01\x00";
+    const BINARY_SERIES: u8 = 0x01;
+    const BINARY_SAMPLE: u8 = 0x02;
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Binary => "bin",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Session {0} is already being recorded")]
+    AlreadyRecording(String),
+    #[error("Session {0} already exists")]
+    Exists(String),
+    #[error("Session name {0:?} may contain only letters, digits, '-', '_' and '.'")]
+    InvalidName(String),
+    #[error("No session is being recorded")]
+    NotRecording,
+    #[error("Failed to write recording, {0}")]
+    Io(#[from] io::Error),
+}
+
+/// File of one format, replaced by the next one when it is full
+struct Writer {
+    format: Format,
+    directory: PathBuf,
+    name: String,
+    index: usize,
+    path: PathBuf,
+    file: BufWriter<File>,
+    written: u64,
+    /// IDs of the series defined in the current binary file
+    series: BTreeMap<String, u16>,
+}
+
+impl Writer {
+    fn create(directory: &Path, name: &str, format: Format, index: usize) -> io::Result<Self> {
+        let path = directory.join(format!("{name}-{index:04}.{}", format.extension()));
+        let mut writer = Self {
+            format,
+            directory: directory.to_owned(),
+            name: name.to_owned(),
+            index,
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            written: 0,
+            series: BTreeMap::new(),
+        };
+        match format {
+            Format::Csv => writer.write_bytes(b"timestamp,series,unit,value,run\n")?,
+            Format::Binary => writer.write_bytes(Format::BINARY_MAGIC)?,
+        }
+        Ok(writer)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, sample: &Sample, max_file_bytes: u64) -> io::Result<()> {
+        if self.written >= max_file_bytes {
+            self.file.flush()?;
+            *self = Self::create(&self.directory, &self.name, self.format, self.index + 1)?;
+        }
+
+        match self.format {
+            Format::Csv => {
+                let value = sample
+                    .value
+                    .map(|value| value.to_string())
+                    .unwrap_or_default();
+                let run = sample.run.map(|run| run.to_string()).unwrap_or_default();
+                let line = format!(
+                    "{:.3},{},{},{value},{run}\n",
+                    sample.timestamp,
+                    csv_field(&sample.series),
+                    csv_field(sample.unit)
+                );
+                self.write_bytes(line.as_bytes())
+            }
+            Format::Binary => {
+                let id = match self.series.get(&sample.series) {
+                    Some(id) => *id,
+                    None => self.define_series(sample)?,
+                };
+                let mut record = vec![Format::BINARY_SAMPLE];
+                record.extend_from_slice(&id.to_le_bytes());
+                record.extend_from_slice(&sample.timestamp.to_le_bytes());
+                let value = sample.value.map_or(f32::NAN, |value| value as f32);
+                record.extend_from_slice(&value.to_le_bytes());
+                let run = sample.run.unwrap_or_default() as u32;
+                record.extend_from_slice(&run.to_le_bytes());
+                self.write_bytes(&record)
+            }
+        }
+    }
+
+    fn define_series(&mut self, sample: &Sample) -> io::Result<u16> {
+        let id = self.series.len() as u16;
+        let mut record = vec![Format::BINARY_SERIES];
+        record.extend_from_slice(&id.to_le_bytes());
+        for text in [sample.series.as_str(), sample.unit] {
+            let len = u16::try_from(text.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Name is too long"))?;
+            record.extend_from_slice(&len.to_le_bytes());
+            record.extend_from_slice(text.as_bytes());
+        }
+        self.write_bytes(&record)?;
+        self.series.insert(sample.series.clone(), id);
+        Ok(id)
+    }
+}
+
+/// Quotes the field when it would break the CSV row, quotes inside are doubled
+pub fn csv_field(text: &str) -> Cow<'_, str> {
+    match text.contains([',', '"', '\n', '\r']) {
+        true => Cow::Owned(format!("\"{}\"", text.replace('"', "\"\""))),
+        false => Cow::Borrowed(text),
+    }
+}
+
+struct Session {
+    name: String,
+    started: SystemTime,
+    samples: u64,
+    writers: Vec<Writer>,
+    last_flush: Instant,
+}
+
+/// Summary of the recorded session
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    pub name: String,
+    /// Unix time of the session start
+    pub started: f64,
+    pub samples: u64,
+    /// Files currently being written
+    pub files: Vec<PathBuf>,
+}
+
+impl Session {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            name: self.name.clone(),
+            started: unix_seconds(self.started),
+            samples: self.samples,
+            files: self
+                .writers
+                .iter()
+                .map(|writer| writer.path.clone())
+                .collect(),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        for writer in self.writers.iter_mut() {
+            writer.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the samples of the active session, if there is any
+#[derive(Clone)]
+pub struct Recorder {
+    descriptor: Descriptor,
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl Recorder {
+    /// Buffered samples are written to the disk at least this often
+    const FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+    pub fn new(descriptor: &Descriptor) -> Self {
+        Self {
+            descriptor: descriptor.clone(),
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn start(&self, name: &str) -> Result<SessionInfo, Error> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(valid) {
+            return Err(Error::InvalidName(name.to_owned()));
+        }
+
+        let mut session = self
+            .session
+            .lock()
+            .expect("BUG: Failed to acquire session lock");
+        if let Some(session) = session.as_ref() {
+            return Err(Error::AlreadyRecording(session.name.clone()));
+        }
+
+        let directory = Path::new(&self.descriptor.directory).join(name);
+        fs::create_dir_all(&self.descriptor.directory)?;
+        fs::create_dir(&directory).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => Error::Exists(name.to_owned()),
+            _ => Error::Io(err),
+        })?;
+
+        let writers = self
+            .descriptor
+            .formats
+            .iter()
+            .map(|format| Writer::create(&directory, name, *format, 0))
+            .collect::<io::Result<_>>()?;
+        let new_session = Session {
+            name: name.to_owned(),
+            started: SystemTime::now(),
+            samples: 0,
+            writers,
+            last_flush: Instant::now(),
+        };
+        info!("Recording session {name} into {}", directory.display());
+        let info = new_session.info();
+        *session = Some(new_session);
+        Ok(info)
+    }
+
+    pub fn stop(&self) -> Result<SessionInfo, Error> {
+        let mut session = self
+            .session
+            .lock()
+            .expect("BUG: Failed to acquire session lock");
+        let mut session = session.take().ok_or(Error::NotRecording)?;
+        session.flush()?;
+        info!(
+            "Recording of session {} stopped, {} samples",
+            session.name, session.samples
+        );
+        Ok(session.info())
+    }
+
+    pub fn status(&self) -> Option<SessionInfo> {
+        let session = self
+            .session
+            .lock()
+            .expect("BUG: Failed to acquire session lock");
+        session.as_ref().map(Session::info)
+    }
+
+    pub fn record(&self, sample: &Sample) -> Result<(), Error> {
+        let mut session = self
+            .session
+            .lock()
+            .expect("BUG: Failed to acquire session lock");
+        let Some(session) = session.as_mut() else {
+            return Ok(());
+        };
+
+        for writer in session.writers.iter_mut() {
+            writer.write(sample, self.descriptor.max_file_bytes)?;
+        }
+        session.samples += 1;
+        if session.last_flush.elapsed() >= Self::FLUSH_PERIOD {
+            session.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes every published sample into the active session
+pub async fn record_periodically(recorder: Recorder, samples: Samples) {
+    let mut receiver = samples.subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok(sample) => {
+                if let Err(err) = recorder.record(&sample) {
+                    warn!("Failed to record sample, {err}");
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Recorder is too slow, {skipped} samples skipped")
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_files() {
+        let directory = std::env::temp_dir().join(format!("recorder-{}", std::process::id()));
+        let recorder = Recorder::new(&Descriptor {
+            directory: directory.to_string_lossy().into_owned(),
+            max_file_bytes: 100,
+            formats: vec![Format::Csv, Format::Binary],
+        });
+
+        recorder.start("test").unwrap();
+        assert!(matches!(
+            recorder.start("other"),
+            Err(Error::AlreadyRecording(_))
+        ));
+        for t in 0..10 {
+            let sample = Sample {
+                series: "netio/power".to_owned(),
+                unit: "W",
+                value: (t % 3 != 0).then_some(t as f64),
+                timestamp: t as f64,
+                run: (t > 5).then_some(7),
+            };
+            recorder.record(&sample).unwrap();
+        }
+        let info = recorder.stop().unwrap();
+        assert_eq!(info.samples, 10);
+
+        let csv = fs::read_to_string(directory.join("test/test-0000.csv")).unwrap();
+        assert!(csv.starts_with("timestamp,series,unit,value,run\n0.000,netio/power,W,,\n"));
+        assert!(directory.join("test/test-0001.csv").exists());
+
+        // Magic, series definition and 4 samples of 19 bytes each fill the first file
+        let binary = fs::read(directory.join("test/test-0000.bin")).unwrap();
+        assert_eq!(&binary[..8], Format::BINARY_MAGIC);
+        assert_eq!(binary.len(), 8 + 19 + 4 * 19);
+        // Every file defines its series again
+        let binary = fs::read(directory.join("test/test-0001.bin")).unwrap();
+        assert_eq!(binary[8], Format::BINARY_SERIES);
+
+        assert!(matches!(recorder.start("test"), Err(Error::Exists(_))));
+        assert!(matches!(recorder.start("../x"), Err(Error::InvalidName(_))));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("scope/psu_voltage"), "scope/psu_voltage");
+        assert_eq!(csv_field("bench 1, left"), "\"bench 1, left\"");
+        assert_eq!(csv_field("12\" fan"), "\"12\"\" fan\"");
+    }
+}
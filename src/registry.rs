@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Label names and values of one series, sorted by the name
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gauge => "gauge",
+            Self::Counter => "counter",
+            Self::Histogram => "histogram",
+        }
+    }
+}
+
+enum Value {
+    /// The value is not exported after the stale time
+    Number(f64, Option<SystemTime>),
+    Histogram {
+        /// Cumulative count per upper bound
+        buckets: Vec<(f64, u64)>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    buckets: Vec<f64>,
+    series: BTreeMap<Labels, Value>,
+}
+
+/// Metrics of all the backends, rendered in the Prometheus text format
+#[derive(Clone, Default)]
+pub struct Registry {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str) -> Gauge {
+        self.register(name, help, Kind::Gauge, Vec::new());
+        Gauge {
+            registry: self.clone(),
+            name,
+            stale_after: None,
+        }
+    }
+
+    pub fn counter(&self, name: &'static str, help: &'static str) -> Counter {
+        self.register(name, help, Kind::Counter, Vec::new());
+        Counter {
+            registry: self.clone(),
+            name,
+        }
+    }
+
+    pub fn histogram(&self, name: &'static str, help: &'static str, buckets: &[f64]) -> Histogram {
+        self.register(name, help, Kind::Histogram, buckets.to_vec());
+        Histogram {
+            registry: self.clone(),
+            name,
+        }
+    }
+
+    fn register(&self, name: &'static str, help: &'static str, kind: Kind, buckets: Vec<f64>) {
+        let mut families = self.lock();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            buckets,
+            series: BTreeMap::new(),
+        });
+        assert_eq!(family.kind, kind, "BUG: Metric {name} registered twice");
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<&'static str, Family>> {
+        self.families
+            .lock()
+            .expect("BUG: Failed to acquire metrics lock")
+    }
+
+    fn update(
+        &self,
+        name: &'static str,
+        labels: &[(&str, &str)],
+        f: impl FnOnce(&mut Family, &mut Option<Value>),
+    ) {
+        let mut families = self.lock();
+        let family = families
+            .get_mut(name)
+            .expect("BUG: Metric is not registered");
+
+        let mut labels: Labels = labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        labels.sort();
+
+        let mut value = family.series.remove(&labels);
+        f(family, &mut value);
+        if let Some(value) = value {
+            family.series.insert(labels, value);
+        }
+    }
+
+    /// Prometheus text exposition format version 0.0.4
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let now = SystemTime::now();
+        for (name, family) in self.lock().iter() {
+            let _ = writeln!(output, "# HELP {name} {}", family.help);
+            let _ = writeln!(output, "# TYPE {name} {}", family.kind.as_str());
+
+            for (labels, value) in family.series.iter() {
+                match value {
+                    Value::Number(_, Some(stale_at)) if *stale_at <= now => {}
+                    // Explicit timestamps would disable the staleness handling of Prometheus,
+                    // the acquisition time is kept in the JSON API
+                    Value::Number(value, _) => {
+                        let _ = writeln!(
+                            output,
+                            "{name}{} {}",
+                            format_labels(labels, None),
+                            format_value(*value)
+                        );
+                    }
+                    Value::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        for (bound, bucket_count) in buckets {
+                            let le = format_value(*bound);
+                            let _ = writeln!(
+                                output,
+                                "{name}_bucket{} {bucket_count}",
+                                format_labels(labels, Some(&le))
+                            );
+                        }
+                        let _ = writeln!(
+                            output,
+                            "{name}_bucket{} {count}",
+                            format_labels(labels, Some("+Inf"))
+                        );
+                        let _ = writeln!(
+                            output,
+                            "{name}_sum{} {}",
+                            format_labels(labels, None),
+                            format_value(*sum)
+                        );
+                        let _ = writeln!(
+                            output,
+                            "{name}_count{} {count}",
+                            format_labels(labels, None)
+                        );
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    match pairs.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", pairs.join(",")),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "NaN".to_string(),
+        v if v == f64::INFINITY => "+Inf".to_string(),
+        v if v == f64::NEG_INFINITY => "-Inf".to_string(),
+        v => v.to_string(),
+    }
+}
+
+/// Value which can go up and down, missing or stale value is not exported at all
+#[derive(Clone)]
+pub struct Gauge {
+    registry: Registry,
+    name: &'static str,
+    stale_after: Option<Duration>,
+}
+
+impl Gauge {
+    /// Values set with the acquisition time are not exported when they get older than this
+    pub fn stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = Some(stale_after);
+        self
+    }
+
+    pub fn set(&self, labels: &[(&str, &str)], value: f64) {
+        self.registry.update(self.name, labels, |_, current| {
+            *current = Some(Value::Number(value, None));
+        });
+    }
+
+    /// Sets the value read at the given time, it is removed from the output once stale
+    pub fn set_at(&self, labels: &[(&str, &str)], value: f64, acquired: SystemTime) {
+        let stale_at = self.stale_after.map(|stale_after| acquired + stale_after);
+        self.registry.update(self.name, labels, |_, current| {
+            *current = Some(Value::Number(value, stale_at));
+        });
+    }
+
+    pub fn remove(&self, labels: &[(&str, &str)]) {
+        self.registry.update(self.name, labels, |_, current| {
+            *current = None;
+        });
+    }
+
+    /// Sets the value read at the given time, or removes the series when there is no value
+    pub fn set_or_remove_at(
+        &self,
+        labels: &[(&str, &str)],
+        value: Option<f64>,
+        acquired: SystemTime,
+    ) {
+        match value {
+            Some(value) => self.set_at(labels, value, acquired),
+            None => self.remove(labels),
+        }
+    }
+}
+
+/// Monotonically increasing value
+#[derive(Clone)]
+pub struct Counter {
+    registry: Registry,
+    name: &'static str,
+}
+
+impl Counter {
+    pub fn inc(&self, labels: &[(&str, &str)]) {
+        self.inc_by(labels, 1.0);
+    }
+
+    pub fn inc_by(&self, labels: &[(&str, &str)], increment: f64) {
+        self.registry.update(self.name, labels, |_, current| {
+            let value = match current {
+                Some(Value::Number(value, _)) => *value,
+                _ => 0.0,
+            };
+            *current = Some(Value::Number(value + increment, None));
+        });
+    }
+}
+
+/// Distribution of observed values in the configured buckets
+#[derive(Clone)]
+pub struct Histogram {
+    registry: Registry,
+    name: &'static str,
+}
+
+impl Histogram {
+    pub fn observe(&self, labels: &[(&str, &str)], observation: f64) {
+        self.registry.update(self.name, labels, |family, current| {
+            let mut value = current.take().unwrap_or_else(|| Value::Histogram {
+                buckets: family.buckets.iter().map(|bound| (*bound, 0)).collect(),
+                sum: 0.0,
+                count: 0,
+            });
+            if let Value::Histogram {
+                buckets,
+                sum,
+                count,
+            } = &mut value
+            {
+                for (bound, bucket_count) in buckets.iter_mut() {
+                    if observation <= *bound {
+                        *bucket_count += 1;
+                    }
+                }
+                *sum += observation;
+                *count += 1;
+            }
+            *current = Some(value);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_exposition_format() {
+        let registry = Registry::new();
+        let gauge = registry.gauge("temperature_c", "Temperature in ˚C");
+        let counter = registry.counter("errors_total", "Number of errors");
+        let histogram = registry.histogram("duration_seconds", "Duration", &[0.1, 1.0]);
+        let stale = registry
+            .gauge("voltage_v", "Voltage")
+            .stale_after(Duration::from_secs(1));
+
+        gauge.set(&[("sensor_id", "1")], 25.5);
+        gauge.set(&[("sensor_id", "2")], 26.0);
+        gauge.set_at(&[("sensor_id", "3")], 27.0, SystemTime::now());
+        stale.set_at(&[], 12.0, SystemTime::now() - Duration::from_secs(2));
+        gauge.remove(&[("sensor_id", "2")]);
+        counter.inc(&[("kind", "open\"thermocouple")]);
+        counter.inc(&[("kind", "open\"thermocouple")]);
+        histogram.observe(&[], 0.5);
+
+        assert_eq!(
+            registry.render(),
+            "# HELP duration_seconds Duration\n\
+             # TYPE duration_seconds histogram\n\
+             duration_seconds_bucket{le=\"0.1\"} 0\n\
+             duration_seconds_bucket{le=\"1\"} 1\n\
+             duration_seconds_bucket{le=\"+Inf\"} 1\n\
+             duration_seconds_sum 0.5\n\
+             duration_seconds_count 1\n\
+             # HELP errors_total Number of errors\n\
+             # TYPE errors_total counter\n\
+             errors_total{kind=\"open\\\"thermocouple\"} 2\n\
+             # HELP temperature_c Temperature in ˚C\n\
+             # TYPE temperature_c gauge\n\
+             temperature_c{sensor_id=\"1\"} 25.5\n\
+             temperature_c{sensor_id=\"3\"} 27\n\
+             # HELP voltage_v Voltage\n\
+             # TYPE voltage_v gauge\n"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::measurement::unix_seconds;
+use crate::scope::{Coupling, Descriptor, Query};
+use crate::scpi::{self, Connection, Error, Instrument};
+use crate::waveform::Waveform;
+use async_trait::async_trait;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Rigol and Keysight command sets differ only in the measurement queries and the offset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Rigol,
+    Keysight,
+}
+
+/// Rigol DS/MSO or Keysight InfiniiVision scope
+pub struct Rigol {
+    connection: Connection,
+    dialect: Dialect,
+}
+
+impl Rigol {
+    pub fn new(connection: Connection, dialect: Dialect) -> Self {
+        Self {
+            connection,
+            dialect,
+        }
+    }
+}
+
+#[async_trait]
+impl Instrument for Rigol {
+    async fn init(&mut self, descriptor: &Descriptor) -> Result<(), Error> {
+        let commands = init_commands(self.dialect, descriptor);
+        self.connection.send_all(&commands).await?;
+        Ok(())
+    }
+
+    async fn measure(&mut self, query: &Query) -> Result<f64, Error> {
+        let reply = self.connection.query(&command(self.dialect, query)).await?;
+        scpi::parse_number(reply, &query.reply_unit)
+    }
+
+    async fn errors(&mut self) -> Result<Vec<String>, Error> {
+        self.connection.error_queue().await
+    }
+
+    async fn waveform(&mut self, channel: u8, points: usize) -> Result<Waveform, Error> {
+        let acquired = SystemTime::now();
+        let commands = [
+            format!(":WAVeform:SOURce CHANnel{channel}"),
+            ":WAVeform:FORMat BYTE".to_owned(),
+            format!(":WAVeform:POINts {points}"),
+        ];
+        self.connection.send_all(&commands).await?;
+
+        let reply = self.connection.query(":WAVeform:PREamble?").await?;
+        let Some(preamble) = Preamble::parse(&reply) else {
+            return Err(Error::UnexpectedReply(reply));
+        };
+        let data = self.connection.query_block(":WAVeform:DATA?").await?;
+
+        Ok(Waveform {
+            timestamp: unix_seconds(acquired),
+            channel,
+            start: preamble.x_origin - preamble.x_reference * preamble.x_increment,
+            interval: preamble.x_increment,
+            values: data
+                .iter()
+                .map(|&code| preamble.volts(self.dialect, code))
+                .collect(),
+        })
+    }
+}
+
+/// Scaling of the waveform data, the format, type, points and count are not needed
+#[derive(Debug, PartialEq)]
+struct Preamble {
+    x_increment: f64,
+    x_origin: f64,
+    x_reference: f64,
+    y_increment: f64,
+    y_origin: f64,
+    y_reference: f64,
+}
+
+impl Preamble {
+    fn parse(reply: &str) -> Option<Self> {
+        let preamble: Vec<f64> = reply
+            .trim()
+            .split(',')
+            .map(f64::from_str)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        let [_, _, _, _, x_increment, x_origin, x_reference, y_increment, y_origin, y_reference] =
+            preamble[..]
+        else {
+            return None;
+        };
+        Some(Self {
+            x_increment,
+            x_origin,
+            x_reference,
+            y_increment,
+            y_origin,
+            y_reference,
+        })
+    }
+
+    /// Codes are unsigned bytes, Rigol origin is in codes, Keysight one in volts
+    fn volts(&self, dialect: Dialect, code: u8) -> f64 {
+        match dialect {
+            Dialect::Rigol => (code as f64 - self.y_origin - self.y_reference) * self.y_increment,
+            Dialect::Keysight => {
+                (code as f64 - self.y_reference) * self.y_increment + self.y_origin
+            }
+        }
+    }
+}
+
+/// Measurements by the PAVA parameter, the same config works with all the scopes,
+/// the Rigol item and the Keysight query
+const MEASUREMENTS: [(&str, &str, &str); 15] = [
+    ("MEAN", "VAVG", "VAVerage"),
+    ("RMS", "VRMS", "VRMS"),
+    ("PKPK", "VPP", "VPP"),
+    ("MAX", "VMAX", "VMAX"),
+    ("MIN", "VMIN", "VMIN"),
+    ("AMPL", "VAMP", "VAMPlitude"),
+    ("TOP", "VTOP", "VTOP"),
+    ("BASE", "VBASe", "VBASe"),
+    ("FREQ", "FREQuency", "FREQuency"),
+    ("PER", "PERiod", "PERiod"),
+    ("DUTY", "PDUTy", "DUTYcycle"),
+    ("WID", "PWIDth", "PWIDth"),
+    ("NWID", "NWIDth", "NWIDth"),
+    ("RISE", "RTIMe", "RISetime"),
+    ("FALL", "FTIMe", "FALLtime"),
+];
+
+/// Measurement of the scope for the PAVA parameter, `None` if it is not supported
+pub fn measurement(dialect: Dialect, parameter: &str) -> Option<&'static str> {
+    let (_, rigol, keysight) = MEASUREMENTS
+        .iter()
+        .find(|(pava, _, _)| pava.eq_ignore_ascii_case(parameter))?;
+    match dialect {
+        Dialect::Rigol => Some(rigol),
+        Dialect::Keysight => Some(keysight),
+    }
+}
+
+/// Coupling and input impedance, `None` if the scope does not support the coupling
+pub fn coupling(dialect: Dialect, coupling: Coupling) -> Option<(&'static str, &'static str)> {
+    match (dialect, coupling) {
+        (_, Coupling::Dc) => Some(("DC", "ONEMeg")),
+        (_, Coupling::Ac) => Some(("AC", "ONEMeg")),
+        (Dialect::Rigol, Coupling::Gnd) => Some(("GND", "ONEMeg")),
+        (Dialect::Keysight, Coupling::Dc50) => Some(("DC", "FIFTy")),
+        (Dialect::Keysight, Coupling::Ac50) => Some(("AC", "FIFTy")),
+        _ => None,
+    }
+}
+
+/// SCPI commands setting up the scope from the reset state, followed by the raw ones
+pub fn init_commands(dialect: Dialect, descriptor: &Descriptor) -> Vec<String> {
+    let mut commands = vec![
+        "*RST".to_owned(),
+        format!(":TIMebase:SCALe {}", descriptor.time_per_div_s),
+    ];
+    for channel in &descriptor.channels {
+        let name = format!(":CHANnel{}", channel.channel);
+        let (coupling, impedance) =
+            coupling(dialect, channel.coupling).expect("BUG: Unsupported scope coupling");
+        // Keysight offset is the voltage in the middle of the screen, it goes the other way
+        let offset = match dialect {
+            Dialect::Rigol => channel.offset_v,
+            Dialect::Keysight => -channel.offset_v,
+        };
+        commands.push(format!("{name}:PROBe {}", channel.attenuation));
+        commands.push(format!("{name}:SCALe {}", channel.volts_per_div));
+        commands.push(format!("{name}:OFFSet {offset}"));
+        commands.push(format!("{name}:COUPling {coupling}"));
+        if dialect == Dialect::Keysight {
+            commands.push(format!("{name}:IMPedance {impedance}"));
+        }
+        let bandwidth_limit = match channel.bandwidth_limit {
+            true => "ON",
+            false => "OFF",
+        };
+        commands.push(format!("{name}:BWLimit {bandwidth_limit}"));
+    }
+    commands.extend(descriptor.init.iter().cloned());
+    commands
+}
+
+pub fn command(dialect: Dialect, query: &Query) -> String {
+    let channel = query
+        .channel
+        .expect("BUG: Scope measurement without channel");
+    let parameter = query
+        .parameter
+        .as_ref()
+        .expect("BUG: Scope measurement without parameter");
+    let measurement = measurement(dialect, parameter).expect("BUG: Unsupported scope measurement");
+    match dialect {
+        Dialect::Rigol => format!(":MEASure:ITEM? {measurement},CHANnel{channel}"),
+        Dialect::Keysight => format!(":MEASure:{measurement}? CHANnel{channel}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_codes_to_volts() {
+        // Rigol DS1054Z at 0.4 V/div and no offset, the screen middle is the code 127
+        let rigol = "0,2,1200,1,1.000000e-08,-6.000000e-06,0,4.000000e-02,0,127";
+        let preamble = Preamble::parse(rigol).unwrap();
+        assert_eq!(preamble.volts(Dialect::Rigol, 127), 0.0);
+        assert_eq!(preamble.volts(Dialect::Rigol, 152), 1.0);
+        assert_eq!(preamble.volts(Dialect::Rigol, 102), -1.0);
+
+        // Keysight at 0.25 V/div with 1.5 V offset, the origin is the voltage of the code 128
+        let keysight = "+0,+0,+1000,+1,+1.00000000E-08,-5.00000000E-06,+0,\
+            +7.81250000E-03,+1.50000000E+00,+128";
+        let preamble = Preamble::parse(keysight).unwrap();
+        assert_eq!(preamble.volts(Dialect::Keysight, 128), 1.5);
+        assert_eq!(preamble.volts(Dialect::Keysight, 192), 2.0);
+        assert_eq!(preamble.volts(Dialect::Keysight, 64), 1.0);
+        assert_eq!(
+            preamble.x_origin - preamble.x_reference * preamble.x_increment,
+            -5e-6
+        );
+
+        assert_eq!(Preamble::parse("0,2,1200"), None);
+    }
+
+    #[test]
+    fn generates_keysight_commands() {
+        let descriptor: Descriptor = toml::from_str(
+            r#"
+            address = "10.34.0.66"
+            port = 5025
+            vendor = "keysight"
+            init = [":ACQuire:TYPE AVERage"]
+
+            [[channels]]
+            channel = 1
+            attenuation = 10
+            volts_per_div = 5.0
+            offset_v = -15.0
+            coupling = "dc50"
+            "#,
+        )
+        .unwrap();
+        descriptor.validate().unwrap();
+
+        assert_eq!(
+            init_commands(Dialect::Keysight, &descriptor),
+            [
+                "*RST",
+                ":TIMebase:SCALe 0.01",
+                ":CHANnel1:PROBe 10",
+                ":CHANnel1:SCALe 5",
+                ":CHANnel1:OFFSet 15",
+                ":CHANnel1:COUPling DC",
+                ":CHANnel1:IMPedance FIFTy",
+                ":CHANnel1:BWLimit OFF",
+                ":ACQuire:TYPE AVERage",
+            ]
+        );
+        let fan_rpm = &descriptor.measurements[1];
+        assert_eq!(
+            command(Dialect::Keysight, fan_rpm),
+            ":MEASure:FREQuency? CHANnel2"
+        );
+        assert_eq!(
+            command(Dialect::Rigol, fan_rpm),
+            ":MEASure:ITEM? FREQuency,CHANnel2"
+        );
+
+        let mut descriptor = descriptor.clone();
+        descriptor.vendor = crate::scope::Vendor::Rigol;
+        assert!(descriptor.validate().is_err());
+    }
+}
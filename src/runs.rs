@@ -0,0 +1,244 @@
+use crate::config::Config;
+use crate::measurement::{unix_seconds, Samples};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use thiserror::Error;
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct Descriptor {
+    /// All the runs are kept in this file, so they survive a restart
+    pub file: String,
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Self {
+            file: "runs.json".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Run {0} is still active, stop it first")]
+    Active(u64),
+    #[error("Run {0} does not exist")]
+    NotFound(u64),
+    #[error("Run {0} is already stopped")]
+    Stopped(u64),
+    #[error("Failed to store runs, {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to parse runs file, {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Everything that affects the measured values, as it was when the run started
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Snapshot {
+    /// Kept as plain JSON, so the old runs can be loaded even when the config format changes
+    pub config: serde_json::Value,
+    /// Calibration offsets actually applied, including the ones from the calibration file
+    pub calibration: BTreeMap<String, f64>,
+}
+
+impl Snapshot {
+    pub fn new(config: &Config, calibration: BTreeMap<String, f64>) -> Self {
+        Self {
+            config: serde_json::to_value(config).expect("BUG: Failed to serialize config"),
+            calibration,
+        }
+    }
+}
+
+/// Parameters of the new run given by the operator
+#[derive(Clone, Deserialize, Debug)]
+pub struct Request {
+    pub name: String,
+    pub dut: Option<String>,
+    #[serde(default)]
+    pub notes: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Run {
+    pub id: u64,
+    pub name: String,
+    pub dut: Option<String>,
+    pub notes: String,
+    /// Unix time of the start
+    pub started: f64,
+    /// Unix time of the stop, `None` while the run is active
+    pub stopped: Option<f64>,
+    pub snapshot: Snapshot,
+}
+
+/// Test runs, at most one is active and tags all the published samples
+#[derive(Clone)]
+pub struct Runs {
+    descriptor: Descriptor,
+    runs: Arc<Mutex<Vec<Run>>>,
+    samples: Samples,
+}
+
+impl Runs {
+    /// Loads the stored runs, the run active before the restart is continued
+    pub fn load(descriptor: &Descriptor, samples: Samples) -> Result<Self, Error> {
+        let runs: Vec<Run> = match File::open(&descriptor.file) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(run) = runs.iter().find(|run| run.stopped.is_none()) {
+            info!("Continuing run {} {}", run.id, run.name);
+            samples.set_run(Some(run.id));
+        }
+        Ok(Self {
+            descriptor: descriptor.clone(),
+            runs: Arc::new(Mutex::new(runs)),
+            samples,
+        })
+    }
+
+    pub fn list(&self) -> Vec<Run> {
+        self.runs
+            .lock()
+            .expect("BUG: Failed to acquire runs lock")
+            .clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Run> {
+        let runs = self.runs.lock().expect("BUG: Failed to acquire runs lock");
+        runs.iter().find(|run| run.id == id).cloned()
+    }
+
+    pub fn start(&self, request: Request, snapshot: Snapshot) -> Result<Run, Error> {
+        let mut runs = self.runs.lock().expect("BUG: Failed to acquire runs lock");
+        if let Some(run) = runs.iter().find(|run| run.stopped.is_none()) {
+            return Err(Error::Active(run.id));
+        }
+
+        let run = Run {
+            id: runs.iter().map(|run| run.id).max().unwrap_or_default() + 1,
+            name: request.name,
+            dut: request.dut,
+            notes: request.notes,
+            started: unix_seconds(SystemTime::now()),
+            stopped: None,
+            snapshot,
+        };
+        // Memory is changed only when the file is written, the run is not lost on restart
+        let mut updated = runs.clone();
+        updated.push(run.clone());
+        self.store(&updated)?;
+        *runs = updated;
+        self.samples.set_run(Some(run.id));
+        info!("Run {} {} started", run.id, run.name);
+        Ok(run)
+    }
+
+    pub fn stop(&self, id: u64) -> Result<Run, Error> {
+        let mut runs = self.runs.lock().expect("BUG: Failed to acquire runs lock");
+        let mut updated = runs.clone();
+        let run = updated
+            .iter_mut()
+            .find(|run| run.id == id)
+            .ok_or(Error::NotFound(id))?;
+        if run.stopped.is_some() {
+            return Err(Error::Stopped(id));
+        }
+
+        run.stopped = Some(unix_seconds(SystemTime::now()));
+        let run = run.clone();
+        self.store(&updated)?;
+        *runs = updated;
+        self.samples.set_run(None);
+        info!("Run {} {} stopped", run.id, run.name);
+        Ok(run)
+    }
+
+    fn store(&self, runs: &[Run]) -> Result<(), Error> {
+        // Written aside first, so the crash does not leave a truncated file
+        let temporary = format!("{}.tmp", self.descriptor.file);
+        fs::write(&temporary, serde_json::to_string_pretty(runs)?)?;
+        fs::rename(&temporary, &self.descriptor.file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str) -> Request {
+        Request {
+            name: name.to_owned(),
+            dut: Some("psu-01".to_owned()),
+            notes: String::new(),
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            config: serde_json::Value::Null,
+            calibration: BTreeMap::from([("tc0".to_owned(), -1.5)]),
+        }
+    }
+
+    /// Run of the next published sample
+    fn tagged_run(samples: &Samples) -> Option<u64> {
+        let mut receiver = samples.subscribe();
+        samples.publish("netio/power", "W", Some(10.0), SystemTime::now());
+        receiver.try_recv().unwrap().run
+    }
+
+    #[test]
+    fn stores_runs() {
+        let directory = std::env::temp_dir().join(format!("runs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let descriptor = Descriptor {
+            file: directory.join("runs.json").to_string_lossy().into_owned(),
+        };
+        let samples = Samples::new();
+        let runs = Runs::load(&descriptor, samples.clone()).unwrap();
+        assert!(runs.list().is_empty());
+
+        let run = runs.start(request("soak"), snapshot()).unwrap();
+        assert_eq!(run.id, 1);
+        assert_eq!(tagged_run(&samples), Some(1));
+        assert!(matches!(
+            runs.start(request("other"), snapshot()),
+            Err(Error::Active(1))
+        ));
+        assert_eq!(runs.list().len(), 1);
+
+        // Active run is continued after the restart
+        let files: Vec<_> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["runs.json"]);
+        let samples = Samples::new();
+        let runs = Runs::load(&descriptor, samples.clone()).unwrap();
+        assert_eq!(tagged_run(&samples), Some(1));
+        assert_eq!(runs.get(1).unwrap().snapshot.calibration["tc0"], -1.5);
+
+        let run = runs.stop(1).unwrap();
+        assert!(run.stopped.is_some());
+        assert_eq!(tagged_run(&samples), None);
+        assert!(matches!(runs.stop(1), Err(Error::Stopped(1))));
+        assert!(matches!(runs.stop(2), Err(Error::NotFound(2))));
+        assert_eq!(runs.start(request("next"), snapshot()).unwrap().id, 2);
+
+        let runs = Runs::load(&descriptor, Samples::new()).unwrap();
+        assert!(runs.get(1).unwrap().stopped.is_some());
+        assert!(runs.get(2).unwrap().stopped.is_none());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::health::Health;
+use crate::measurement::Samples;
+use crate::registry::{Counter, Gauge, Histogram, Registry};
+use log::debug;
+use tokio::time::{interval_at, Duration, Instant, Interval, MissedTickBehavior};
+
+/// Shared clock of all the periodic tasks, every period is counted from the same epoch,
+/// so the tasks with the same period tick together and do not drift apart
+#[derive(Clone)]
+pub struct Scheduler {
+    epoch: Instant,
+    period: Gauge,
+    jitter: Histogram,
+    overruns: Counter,
+}
+
+impl Scheduler {
+    pub fn new(registry: &Registry) -> Self {
+        Self {
+            epoch: Instant::now(),
+            period: registry.gauge(
+                "scheduler_period_seconds",
+                "Configured period of the periodic task",
+            ),
+            jitter: registry.histogram(
+                "scheduler_tick_jitter_seconds",
+                "Delay between the scheduled and the actual start of the task iteration",
+                &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1],
+            ),
+            overruns: registry.counter(
+                "scheduler_overruns_total",
+                "Number of ticks skipped because the previous iteration took too long",
+            ),
+        }
+    }
+
+    pub fn ticker(&self, task: &'static str, period: Duration) -> Ticker {
+        self.period.set(&[("task", task)], period.as_secs_f64());
+        Ticker {
+            task,
+            period,
+            interval: None,
+            last: None,
+            scheduler: self.clone(),
+        }
+    }
+
+    /// Interval ticking at the multiples of the period since the epoch, starting with the next one
+    fn interval(&self, period: Duration) -> Interval {
+        let ticks = self.epoch.elapsed().as_nanos() / period.as_nanos() + 1;
+        let start = self.epoch + Duration::from_nanos((period.as_nanos() * ticks) as u64);
+        let mut interval = interval_at(start, period);
+        // Late ticks are dropped to stay on the shared grid instead of bursting to catch up
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        interval
+    }
+}
+
+/// Handles shared by all the periodic tasks
+#[derive(Clone)]
+pub struct TaskContext {
+    pub registry: Registry,
+    pub samples: Samples,
+    pub health: Health,
+}
+
+pub struct Ticker {
+    task: &'static str,
+    period: Duration,
+    /// Started with the first tick, so the slow task initialization is not counted as overrun
+    interval: Option<Interval>,
+    last: Option<Instant>,
+    scheduler: Scheduler,
+}
+
+impl Ticker {
+    /// Waits for the next tick and records how late it came
+    pub async fn tick(&mut self) {
+        let interval = self
+            .interval
+            .get_or_insert_with(|| self.scheduler.interval(self.period));
+        let scheduled = interval.tick().await;
+        let labels = [("task", self.task)];
+        self.scheduler
+            .jitter
+            .observe(&labels, scheduled.elapsed().as_secs_f64());
+
+        if let Some(last) = self.last {
+            let skipped =
+                ((scheduled - last).as_nanos() / self.period.as_nanos()).saturating_sub(1);
+            if skipped > 0 {
+                debug!("Task {} overran, {skipped} ticks skipped", self.task);
+                self.scheduler.overruns.inc_by(&labels, skipped as f64);
+            }
+        }
+        self.last = Some(scheduled);
+    }
+}
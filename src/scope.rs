@@ -1,134 +1,632 @@
-use anyhow::{anyhow, Context, Result};
-use futures::{SinkExt, StreamExt};
-use serde::Deserialize;
-use std::str::FromStr;
+use crate::measurement::{Measurement, Polling};
+use crate::registry::Gauge;
+use crate::rigol::{self, Dialect};
+use crate::scheduler::{TaskContext, Ticker};
+use crate::scpi::{self, Error, Identity};
+use crate::waveform::Request;
+use crate::{siglent, simulation};
+use anyhow::bail;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
-use tokio::net::TcpStream;
-use tokio::time::{sleep, timeout, Duration};
-use tokio_util::codec::{Framed, LinesCodec};
+use std::time::SystemTime;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Descriptor {
+    /// Prefix of the published series and the `instrument` label of its metrics,
+    /// e.g. `dmm` for a DMM
+    #[serde(default = "Descriptor::default_name")]
+    pub name: String,
     pub address: String,
     pub port: usize,
+    /// Command set of the instrument
+    #[serde(default)]
+    pub vendor: Vendor,
+    #[serde(flatten)]
+    pub polling: Polling,
+    /// Horizontal scale in seconds per division
+    #[serde(default = "Descriptor::default_time_per_div_s")]
+    pub time_per_div_s: f64,
+    /// Vertical setup of the used channels, the others are left as they are after reset
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+    /// Raw SCPI commands sent after the setup, the only setup of the generic instrument
+    #[serde(default)]
+    pub init: Vec<String>,
+    /// Setup commands rejected by the instrument fail the connection, otherwise they are logged
+    #[serde(default)]
+    pub strict: bool,
+    /// Values read from the scope every poll interval
+    #[serde(default = "Descriptor::default_measurements")]
+    pub measurements: Vec<Query>,
 }
 
 impl Descriptor {
+    fn default_name() -> String {
+        "scope".to_owned()
+    }
+
+    fn default_time_per_div_s() -> f64 {
+        0.01
+    }
+
+    /// PSU voltage on C1 and fan speed on C2, the fan gives 2 pulses per revolution
+    fn default_measurements() -> Vec<Query> {
+        vec![
+            Query {
+                name: "psu_voltage".to_owned(),
+                channel: Some(1),
+                parameter: Some("MEAN".to_owned()),
+                command: None,
+                reply_unit: "V".to_owned(),
+                scale: 1.0,
+                offset: 0.0,
+                unit: "V".to_owned(),
+                metric: "scope_voltage_v".to_owned(),
+            },
+            Query {
+                name: "fan_rpm".to_owned(),
+                channel: Some(2),
+                parameter: Some("FREQ".to_owned()),
+                command: None,
+                reply_unit: "Hz".to_owned(),
+                scale: 30.0,
+                offset: 0.0,
+                unit: "RPM".to_owned(),
+                metric: "scope_fan_rpm".to_owned(),
+            },
+        ]
+    }
+
     pub fn resource(&self) -> String {
         format!("{}:{}", self.address, self.port)
     }
-}
 
-pub struct Scope {
-    control: Framed<TcpStream, LinesCodec>,
-}
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.time_per_div_s <= 0.0 {
+            bail!("Scope time per division must be positive");
+        }
+        if self.name.is_empty() || self.name.contains('/') {
+            bail!("Instrument name {:?} is not valid", self.name);
+        }
+        if RESERVED_NAMES.contains(&self.name.as_str()) {
+            bail!(
+                "Instrument name {} is already used by another backend",
+                self.name
+            );
+        }
+        if self.vendor == Vendor::Generic && !self.channels.is_empty() {
+            bail!("Generic instrument is set up only by the raw init commands");
+        }
+        let mut channels = BTreeSet::new();
+        for channel in &self.channels {
+            if !(1..=Channel::COUNT).contains(&channel.channel) {
+                bail!("Scope channel {} does not exist", channel.channel);
+            }
+            if !channels.insert(channel.channel) {
+                bail!(
+                    "Scope channel {} is configured more than once",
+                    channel.channel
+                );
+            }
+            if channel.attenuation <= 0.0 || channel.volts_per_div <= 0.0 {
+                bail!(
+                    "Scope channel {} attenuation and V/div must be positive",
+                    channel.channel
+                );
+            }
+            if let Some(dialect) = self.vendor.dialect() {
+                if rigol::coupling(dialect, channel.coupling).is_none() {
+                    bail!(
+                        "Scope channel {} coupling {:?} is not supported by {:?}",
+                        channel.channel,
+                        channel.coupling,
+                        self.vendor
+                    );
+                }
+            }
+        }
 
-impl Scope {
-    const COMMAND_REPLY_TIMEOUT: Duration = Duration::from_millis(3000);
+        let mut names = BTreeSet::new();
+        let mut metrics = BTreeSet::new();
+        for query in &self.measurements {
+            if !names.insert(query.name.as_str()) {
+                bail!("Scope measurement {} is used more than once", query.name);
+            }
+            if !metrics.insert(query.metric.as_str()) {
+                bail!("Scope metric {} is used more than once", query.metric);
+            }
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+            if query.metric.is_empty()
+                || query.metric.starts_with(|c: char| c.is_ascii_digit())
+                || !query.metric.chars().all(valid)
+            {
+                bail!("Scope metric name {:?} is not valid", query.metric);
+            }
+            // Prefix covers also the suffixed series of the histograms
+            if RESERVED_METRIC_PREFIXES
+                .iter()
+                .any(|prefix| query.metric.starts_with(prefix))
+            {
+                bail!("Scope metric {} is reserved by the station", query.metric);
+            }
+            if query.scale == 0.0 {
+                bail!("Scope measurement {} scale must not be zero", query.name);
+            }
+            match self.vendor {
+                Vendor::Generic => self.validate_command(query)?,
+                _ => self.validate_parameter(query)?,
+            }
+        }
+        Ok(())
+    }
 
-    pub async fn open(resource_addr: &str) -> Self {
-        let tcp_stream = timeout(
-            Duration::from_millis(2000),
-            TcpStream::connect(resource_addr),
-        )
-        .await
-        .expect("BUG: connection timeout")
-        .expect("BUG: Cannot connect to scope");
+    /// Checks the instruments can be told apart by their series and metrics
+    pub fn validate_all(instruments: &[Self]) -> anyhow::Result<()> {
+        let mut names = BTreeSet::new();
+        let mut metrics = BTreeSet::new();
+        for instrument in instruments {
+            instrument.validate()?;
+            instrument
+                .polling
+                .validate(&format!("instruments.{}", instrument.name))?;
+            if !names.insert(instrument.name.as_str()) {
+                bail!("Instrument name {} is used more than once", instrument.name);
+            }
+            for query in &instrument.measurements {
+                if !metrics.insert(query.metric.as_str()) {
+                    bail!("Metric {} is used by more instruments", query.metric);
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let control = Framed::new(tcp_stream, LinesCodec::new());
+    /// Generic instruments are queried with the raw command only
+    fn validate_command(&self, query: &Query) -> anyhow::Result<()> {
+        if query.command.as_deref().unwrap_or_default().is_empty() {
+            bail!("Measurement {} needs the raw command", query.name);
+        }
+        if query.channel.is_some() || query.parameter.is_some() {
+            bail!(
+                "Measurement {} of the generic instrument has no channel or parameter",
+                query.name
+            );
+        }
+        Ok(())
+    }
 
-        Self { control }
+    /// Scopes measure the parameter of the channel
+    fn validate_parameter(&self, query: &Query) -> anyhow::Result<()> {
+        let (Some(channel), Some(parameter)) = (query.channel, &query.parameter) else {
+            bail!(
+                "Scope measurement {} needs the channel and parameter",
+                query.name
+            );
+        };
+        if query.command.is_some() {
+            bail!(
+                "Raw command of scope measurement {} is used only by the generic instrument",
+                query.name
+            );
+        }
+        if !(1..=Channel::COUNT).contains(&channel) {
+            bail!(
+                "Scope measurement {} uses channel {} which does not exist",
+                query.name,
+                channel
+            );
+        }
+        if !parameter.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("Scope measurement parameter {:?} is not valid", parameter);
+        }
+        if let Some(dialect) = self.vendor.dialect() {
+            if rigol::measurement(dialect, parameter).is_none() {
+                bail!(
+                    "Scope measurement parameter {:?} is not supported by {:?}",
+                    parameter,
+                    self.vendor
+                );
+            }
+        }
+        Ok(())
     }
+}
 
-    pub async fn init(&mut self) -> Result<()> {
-        self.send("*RST").await?;
-        // Timebase 1 ms / div
-        self.send("TDIV 10MS").await?;
+/// Command set of the instrument
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Vendor {
+    #[default]
+    Siglent,
+    Rigol,
+    Keysight,
+    /// Any SCPI instrument, e.g. a DMM, set up and queried with the raw commands
+    Generic,
+}
 
-        // Channel for PSU voltage measurement
-        self.send("C1:ATTN 10").await?;
-        self.send("C1:VDIV 5V").await?;
-        self.send("C1:OFST -15V").await?;
+impl Vendor {
+    fn dialect(&self) -> Option<Dialect> {
+        match self {
+            Self::Rigol => Some(Dialect::Rigol),
+            Self::Keysight => Some(Dialect::Keysight),
+            Self::Siglent | Self::Generic => None,
+        }
+    }
 
-        // Channel for Fan RPM measurement
-        self.send("C2:ATTN 10").await?;
-        self.send("C2:VDIV 1V").await?;
-        self.send("C2:OFST -3V").await?;
+    /// Whether the identified instrument is made by the vendor, generic is any of them
+    pub fn matches(&self, identity: &Identity) -> bool {
+        let manufacturer = identity.manufacturer.to_uppercase();
+        match self {
+            Self::Siglent => manufacturer.contains("SIGLENT"),
+            Self::Rigol => manufacturer.contains("RIGOL"),
+            // Keysight was Agilent before 2014
+            Self::Keysight => manufacturer.contains("KEYSIGHT") || manufacturer.contains("AGILENT"),
+            Self::Generic => true,
+        }
+    }
 
-        sleep(Duration::from_millis(5000)).await;
-        Ok(())
+    /// Query reading the measurement
+    pub fn command(&self, query: &Query) -> String {
+        match self {
+            Self::Siglent => siglent::command(query),
+            Self::Rigol => rigol::command(Dialect::Rigol, query),
+            Self::Keysight => rigol::command(Dialect::Keysight, query),
+            Self::Generic => query
+                .command
+                .clone()
+                .expect("BUG: Generic measurement without command"),
+        }
     }
 
-    pub async fn send(&mut self, payload: &str) -> Result<()> {
-        self.control.send(payload.to_string()).await?;
-        Ok(())
+    /// Reply of the instrument to the measurement query, used by the simulation
+    pub fn reply(&self, query: &Query, value: Option<f64>) -> String {
+        match self {
+            Self::Siglent => siglent::reply(query, value),
+            _ => scpi::reply(value, &query.reply_unit),
+        }
     }
+}
 
-    async fn recv(&mut self) -> Result<String> {
-        let response = match timeout(Self::COMMAND_REPLY_TIMEOUT, self.control.next())
-            .await
-            .context("PSU SCPI waiting response")?
-        {
-            Some(result) => result.context("PSU SCPI reading response")?,
-            None => anyhow::bail!("Unexpected end of PSU control stream"),
-        };
-        Ok(response)
+/// Vertical setup of one analog channel
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Channel {
+    /// Channel number, 1 is the first one
+    pub channel: u8,
+    /// Probe attenuation, e.g. 10 for a 10:1 probe
+    #[serde(default = "Channel::default_attenuation")]
+    pub attenuation: f64,
+    pub volts_per_div: f64,
+    /// Vertical offset in V, negative moves the trace down
+    #[serde(default)]
+    pub offset_v: f64,
+    #[serde(default)]
+    pub coupling: Coupling,
+    /// Limits the bandwidth to 20 MHz to suppress the noise
+    #[serde(default)]
+    pub bandwidth_limit: bool,
+}
+
+impl Channel {
+    pub const COUNT: u8 = 4;
+
+    fn default_attenuation() -> f64 {
+        1.0
     }
+}
 
-    pub async fn read_psu_voltage(&mut self) -> Result<f64> {
-        self.send("C1:PAVA? MEAN").await?;
-        let response = self.recv().await?;
+/// Value read from the instrument, e.g. the mean voltage or the frequency of a scope channel
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Query {
+    /// Published as the `<instrument name>/<name>` series
+    pub name: String,
+    /// Channel number of the scope, 1 is the first one
+    pub channel: Option<u8>,
+    /// PAVA parameter, e.g. MEAN, RMS, PKPK, FREQ or DUTY, mapped to the other scopes
+    pub parameter: Option<String>,
+    /// Raw SCPI query of the generic instrument, e.g. `MEAS:VOLT:DC?`
+    pub command: Option<String>,
+    /// Unit the instrument appends to the value, e.g. V, Hz or %
+    #[serde(default)]
+    pub reply_unit: String,
+    /// Reported value is `scale * value + offset`
+    #[serde(default = "Query::default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    /// Unit of the reported value
+    pub unit: String,
+    /// Prometheus gauge with the reported value
+    pub metric: String,
+}
 
-        match response.trim().split(',').nth(1) {
-            None => Err(anyhow!("Received wrong response")),
-            Some(value) => {
-                let value = value.replace('V', "");
-                Ok(f64::from_str(&value)?)
-            }
-        }
+impl Query {
+    fn default_scale() -> f64 {
+        1.0
     }
 
-    pub async fn read_fan_rpm(&mut self) -> Result<f64> {
-        self.send("C2:PAVA? FREQ").await?;
-        let response = self.recv().await?;
-        match response.trim().split(',').nth(1) {
-            None => Err(anyhow!("Received wrong response")),
-            Some(value) => {
-                let value = value.replace("Hz", "");
-                let mut fan_rpm = f64::from_str(&value)?;
-                // Frequency to RPM, there are 2 pulses per fan revolution
-                fan_rpm *= 60.0 / 2.0;
-                Ok(fan_rpm)
-            }
+    /// What is measured, e.g. `MEAN of channel 1` or the raw query
+    pub fn description(&self) -> String {
+        match (&self.parameter, self.channel) {
+            (Some(parameter), Some(channel)) => format!("{parameter} of channel {channel}"),
+            _ => self.command.clone().unwrap_or_default(),
         }
     }
+
+    pub fn series(&self, instrument: &str) -> String {
+        format!("{instrument}/{}", self.name)
+    }
+
+    /// Value as read from the scope to the reported one
+    pub fn convert(&self, value: f64) -> f64 {
+        self.scale * value + self.offset
+    }
+
+    /// Reported value back to the one the scope would read, used by the simulation
+    pub fn invert(&self, value: f64) -> f64 {
+        (value - self.offset) / self.scale
+    }
+}
+
+/// Input coupling, the input impedance is 1 MΩ unless it is the 50 Ω variant
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Coupling {
+    #[default]
+    Dc,
+    Ac,
+    Dc50,
+    Ac50,
+    Gnd,
 }
 
-pub async fn update_voltage_periodically(
+/// Prefixes of the metrics registered by the station itself,
+/// the configured measurement metrics can not clash with them
+const RESERVED_METRIC_PREFIXES: [&str; 6] = [
+    "backend_",
+    "ds18b20_",
+    "instrument_",
+    "max6675_",
+    "netio_",
+    "scheduler_",
+];
+/// Backends and series prefixes of the station, an instrument can not be named after them
+const RESERVED_NAMES: [&str; 5] = ["ambient", "ds18b20", "max6675", "netio", "sensors"];
+
+/// Static name for the registry and the samples, created once per configured measurement
+fn leak(text: String) -> &'static str {
+    Box::leak(text.into_boxed_str())
+}
+
+/// Queries the instrument measurements and captures the requested waveforms,
+/// the lost connection is reopened until the instrument answers
+pub async fn update_measurements_periodically(
     descriptor: Descriptor,
-    psu_voltage: Arc<Mutex<Option<f64>>>,
-    fan_rpm: Arc<Mutex<Option<f64>>>,
+    simulation: Option<simulation::Descriptor>,
+    readings: BTreeMap<String, Arc<Mutex<Measurement>>>,
+    mut requests: mpsc::Receiver<Request>,
+    mut ticker: Ticker,
+    context: TaskContext,
 ) {
-    const UPDATE_PERIOD_MS: Duration = Duration::from_millis(400);
-
-    let mut scope = Scope::open(&descriptor.resource()).await;
-    scope.init().await.expect("BUG: Failed to initialize scope");
+    let TaskContext {
+        registry,
+        samples,
+        health,
+    } = context;
+    let name = leak(descriptor.name.clone());
+    let gauges: Vec<Gauge> = descriptor
+        .measurements
+        .iter()
+        .map(|query| {
+            let help = format!(
+                "Instrument {} {} in {}",
+                descriptor.name,
+                query.description(),
+                query.unit
+            );
+            registry
+                .gauge(leak(query.metric.clone()), leak(help))
+                .stale_after(descriptor.polling.stale_after())
+        })
+        .collect();
+    let units: Vec<&'static str> = descriptor
+        .measurements
+        .iter()
+        .map(|query| leak(query.unit.clone()))
+        .collect();
+    let query_duration = registry.histogram(
+        "instrument_query_duration_seconds",
+        "Time to get the reply of the instrument measurement query",
+        &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+    );
+    let connected = registry.gauge(
+        "instrument_connected",
+        "Whether the instrument control connection is open and initialized",
+    );
+    let reconnects = registry.counter(
+        "instrument_reconnects_total",
+        "Number of times the lost instrument connection was reopened",
+    );
+    let info = registry.gauge(
+        "instrument_info",
+        "Identification of the connected instrument, the value is always 1",
+    );
+    let rejected = registry.counter(
+        "instrument_rejected_commands_total",
+        "Number of setup commands rejected by the instrument",
+    );
+    let labels = [("instrument", name)];
 
+    connected.set(&labels, 0.0);
+    let mut identity: Option<Identity> = None;
     loop {
-        let psu_voltage_reading = scope.read_psu_voltage().await.ok();
-        {
-            let mut psu_voltage = psu_voltage
-                .lock()
-                .expect("BUG: Failed to acquire voltagelock");
-            *psu_voltage = psu_voltage_reading;
+        let session = health
+            .connect_unless(name, Error::is_terminal, || {
+                scpi::connect(&descriptor, simulation.as_ref())
+            })
+            .await;
+        // Waveform requests fail once the receiver is dropped
+        let Ok(session) = session else {
+            return;
+        };
+        if let Some(previous) = identity.replace(session.identity.clone()) {
+            info.remove(&previous.labels(name));
         }
+        info.set(&session.identity.labels(name), 1.0);
+        rejected.inc_by(&labels, session.rejected.len() as f64);
+        connected.set(&labels, 1.0);
+        let mut scope = session.instrument;
+
+        loop {
+            let request = select! {
+                _ = ticker.tick() => None,
+                Some(request) = requests.recv() => Some(request),
+            };
+            let lost = match request {
+                Some(request) => {
+                    let result = scope.waveform(request.channel, request.points).await;
+                    if let Err(err) = &result {
+                        warn!(
+                            "Failed to capture {name} waveform of channel {}, {err}",
+                            request.channel
+                        );
+                    }
+                    let lost = result.as_ref().is_err_and(Error::is_connection_lost);
+                    // The API may have stopped waiting for the waveform
+                    let _ = request.reply.send(result);
+                    lost
+                }
+                None => {
+                    let mut error: Option<Error> = None;
+                    for ((query, gauge), unit) in
+                        descriptor.measurements.iter().zip(&gauges).zip(&units)
+                    {
+                        let start = Instant::now();
+                        let acquired = SystemTime::now();
+                        let result = match &error {
+                            // The reply would not come over the lost connection
+                            Some(err) if err.is_connection_lost() => Err(Error::Disconnected),
+                            _ => scope.measure(query).await.map(|value| query.convert(value)),
+                        };
+                        let reading = match result {
+                            Ok(value) => Some(value),
+                            Err(err) => {
+                                warn!("Failed to read {name} measurement {}, {err}", query.name);
+                                error = Some(err);
+                                None
+                            }
+                        };
+                        if let Some(measurement) = readings.get(&query.name) {
+                            measurement
+                                .lock()
+                                .expect("BUG: Failed to acquire instrument measurement lock")
+                                .update(reading, acquired);
+                        }
+                        query_duration.observe(
+                            &[("instrument", name), ("query", &query.name)],
+                            start.elapsed().as_secs_f64(),
+                        );
+                        gauge.set_or_remove_at(&labels, reading, acquired);
+                        samples.publish(query.series(name), unit, reading, acquired);
+                    }
+                    health.report(name, error.as_ref());
+                    error.as_ref().is_some_and(Error::is_connection_lost)
+                }
+            };
 
-        let fan_rpm_reading = scope.read_fan_rpm().await.ok();
-        {
-            let mut fan_rpm = fan_rpm.lock().expect("BUG: Failed to acquire voltagelock");
-            *fan_rpm = fan_rpm_reading;
+            if lost {
+                break;
+            }
         }
 
-        sleep(UPDATE_PERIOD_MS).await;
+        warn!("Instrument {name} connection lost, reconnecting");
+        connected.set(&labels, 0.0);
+        reconnects.inc(&labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_measurements() {
+        let descriptor: Descriptor = toml::from_str(
+            r#"
+            address = "10.34.0.65"
+            port = 5025
+            "#,
+        )
+        .unwrap();
+        descriptor.validate().unwrap();
+
+        let fan_rpm = &descriptor.measurements[1];
+        assert_eq!(descriptor.vendor.command(fan_rpm), "C2:PAVA? FREQ");
+        assert_eq!(fan_rpm.series(&descriptor.name), "scope/fan_rpm");
+        assert_eq!(fan_rpm.convert(40.0), 1200.0);
+        assert_eq!(fan_rpm.invert(1200.0), 40.0);
+
+        let mut descriptor = descriptor.clone();
+        descriptor.measurements[1].metric = "scope_voltage_v".to_owned();
+        assert!(descriptor.validate().is_err());
+        descriptor.measurements[1].metric = "netio_power_w".to_owned();
+        assert!(descriptor.validate().is_err());
+        descriptor.measurements[1].metric = "instrument_query_duration_seconds_count".to_owned();
+        assert!(descriptor.validate().is_err());
+    }
+
+    #[test]
+    fn validates_generic_measurements() {
+        let descriptor: Descriptor = toml::from_str(
+            r#"
+            name = "dmm"
+            address = "10.34.0.70"
+            port = 5025
+            vendor = "generic"
+            init = ["*RST", "CONF:VOLT:DC 10"]
+
+            [[measurements]]
+            name = "voltage"
+            command = "READ?"
+            unit = "V"
+            metric = "dmm_voltage_v"
+            "#,
+        )
+        .unwrap();
+        descriptor.validate().unwrap();
+        assert_eq!(
+            descriptor.vendor.command(&descriptor.measurements[0]),
+            "READ?"
+        );
+
+        let mut descriptor = descriptor.clone();
+        descriptor.measurements[0].channel = Some(1);
+        assert!(descriptor.validate().is_err());
+    }
+
+    #[test]
+    fn validates_instrument_names() {
+        let scope: Descriptor = toml::from_str(
+            r#"
+            address = "10.34.0.65"
+            port = 5025
+            "#,
+        )
+        .unwrap();
+        let mut dmm = scope.clone();
+        dmm.name = "dmm".to_owned();
+        dmm.measurements.truncate(1);
+        dmm.measurements[0].metric = "dmm_voltage_v".to_owned();
+        Descriptor::validate_all(&[scope.clone(), dmm.clone()]).unwrap();
+
+        assert!(Descriptor::validate_all(&[scope.clone(), scope.clone()]).is_err());
+        dmm.measurements[0].metric = "scope_voltage_v".to_owned();
+        assert!(Descriptor::validate_all(&[scope.clone(), dmm.clone()]).is_err());
+        dmm.name = "netio".to_owned();
+        assert!(dmm.validate().is_err());
     }
 }
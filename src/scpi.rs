@@ -0,0 +1,467 @@
+use crate::rigol::{Dialect, Rigol};
+use crate::scope::{Descriptor, Query, Vendor};
+use crate::siglent::Siglent;
+use crate::simulation;
+use crate::waveform::Waveform;
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use log::warn;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Time for the measurements of the scope to settle after the setup
+const SETTLE_TIME: Duration = Duration::from_millis(5000);
+
+/// Value replied by SCPI instruments when they could not measure, e.g. without a signal
+const INVALID: f64 = 9.9e37;
+
+/// Waveforms are transferred as one byte per sample, a longer block is a garbled header
+const MAX_BLOCK_LEN: usize = Waveform::MAX_POINTS;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Timeout waiting for the reply")]
+    Timeout,
+    #[error("Unexpected end of the instrument control stream")]
+    Disconnected,
+    #[error("Failed to connect to instrument, {0}")]
+    Io(#[from] io::Error),
+    #[error("Unexpected reply {0:?}")]
+    UnexpectedReply(String),
+    #[error("Failed to parse value from {0:?}")]
+    Parse(String),
+    #[error("Instrument could not measure the value, {0:?}")]
+    Invalid(String),
+    #[error("Invalid binary block header {0:?}")]
+    Block(String),
+    #[error("Instrument does not support the waveform capture")]
+    Unsupported,
+    #[error("Unexpected instrument {0}")]
+    UnexpectedInstrument(String),
+    #[error("Instrument rejected the setup commands, {0}")]
+    Rejected(String),
+}
+
+impl Error {
+    /// Connection can not be used anymore, e.g. the instrument rebooted,
+    /// a late reply after the timeout would be taken as the reply of the next query
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout | Self::Disconnected | Self::Io(_) | Self::Block(_)
+        )
+    }
+
+    /// Reconnecting does not help, the config or the instrument has to be changed
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::UnexpectedInstrument(_) | Self::Rejected(_))
+    }
+}
+
+/// Instrument feeding the configured measurements, every vendor has its own command set
+#[async_trait]
+pub trait Instrument: Send {
+    /// Resets the instrument and sets it up as configured
+    async fn init(&mut self, descriptor: &Descriptor) -> Result<(), Error>;
+
+    /// Reads the measurement as the instrument reports it, before the conversion
+    async fn measure(&mut self, query: &Query) -> Result<f64, Error>;
+
+    /// Errors of the commands rejected since the last check, empty if all of them succeeded
+    async fn errors(&mut self) -> Result<Vec<String>, Error>;
+
+    /// Captures the waveform of the channel with at most `points` points
+    async fn waveform(&mut self, _channel: u8, _points: usize) -> Result<Waveform, Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// `*IDN?` reply, e.g. `Siglent Technologies,SDS1204X-E,SDS1EBAX2R0000,8.2.6.1.37R2`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+}
+
+impl Identity {
+    pub fn parse(reply: String) -> Result<Self, Error> {
+        let fields: Vec<&str> = reply.trim().split(',').map(str::trim).collect();
+        let [manufacturer, model, serial, firmware] = fields[..] else {
+            return Err(Error::UnexpectedReply(reply));
+        };
+        Ok(Self {
+            manufacturer: manufacturer.to_owned(),
+            model: model.to_owned(),
+            serial: serial.to_owned(),
+            firmware: firmware.to_owned(),
+        })
+    }
+
+    /// Labels of the `instrument_info` metric of the named instrument
+    pub fn labels<'a>(&'a self, instrument: &'a str) -> [(&'static str, &'a str); 5] {
+        [
+            ("instrument", instrument),
+            ("manufacturer", &self.manufacturer),
+            ("model", &self.model),
+            ("serial", &self.serial),
+            ("firmware", &self.firmware),
+        ]
+    }
+}
+
+/// Instrument set up by `connect`
+pub struct Session {
+    pub instrument: Box<dyn Instrument>,
+    pub identity: Identity,
+    /// Errors of the setup commands rejected by the instrument
+    pub rejected: Vec<String>,
+}
+
+/// Opens the instrument, or the simulated one when the simulation is given,
+/// checks it is of the configured vendor and sets it up
+pub async fn connect(
+    descriptor: &Descriptor,
+    simulation: Option<&simulation::Descriptor>,
+) -> Result<Session, Error> {
+    let mut connection = match simulation {
+        Some(simulation) => Connection::simulated(descriptor, simulation),
+        None => Connection::open(&descriptor.resource()).await?,
+    };
+    let identity = Identity::parse(connection.query("*IDN?").await?)?;
+    if !descriptor.vendor.matches(&identity) {
+        return Err(Error::UnexpectedInstrument(format!(
+            "{} {}, expected {:?}",
+            identity.manufacturer, identity.model, descriptor.vendor
+        )));
+    }
+
+    let mut instrument: Box<dyn Instrument> = match descriptor.vendor {
+        Vendor::Siglent => Box::new(Siglent::new(connection)),
+        Vendor::Rigol => Box::new(Rigol::new(connection, Dialect::Rigol)),
+        Vendor::Keysight => Box::new(Rigol::new(connection, Dialect::Keysight)),
+        Vendor::Generic => Box::new(Generic::new(connection)),
+    };
+    instrument.init(descriptor).await?;
+    // Let the measurements of the scope settle after the setup
+    if descriptor.vendor != Vendor::Generic {
+        sleep(SETTLE_TIME).await;
+    }
+
+    let rejected = instrument.errors().await?;
+    for error in &rejected {
+        warn!(
+            "{} {} rejected a setup command, {error}",
+            identity.manufacturer, identity.model
+        );
+    }
+    if descriptor.strict && !rejected.is_empty() {
+        return Err(Error::Rejected(rejected.join("; ")));
+    }
+    Ok(Session {
+        instrument,
+        identity,
+        rejected,
+    })
+}
+
+/// Byte stream carrying the SCPI commands
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Reply of the instrument, a text line or a binary block
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Line(String),
+    Block(Vec<u8>),
+}
+
+/// SCPI commands and replies are lines, except the binary block replies
+#[derive(Default)]
+struct Codec {
+    /// Reply of the binary query is expected, it may be prefixed with a header, e.g. `C1:WF DAT2,`
+    block: bool,
+}
+
+impl Decoder for Codec {
+    type Item = Reply;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Reply>, Error> {
+        let newline = buf.iter().position(|&byte| byte == b'\n');
+        let hash = buf.iter().position(|&byte| byte == b'#');
+        if let Some(start) = hash.filter(|hash| self.block && newline.is_none_or(|nl| *hash < nl)) {
+            let Some((offset, len)) = block_header(&buf[start..])? else {
+                return Ok(None);
+            };
+            let block = buf.split_to(start + offset + len);
+            // The line feed terminating the block is skipped as an empty line
+            return Ok(Some(Reply::Block(block[start + offset..].to_vec())));
+        }
+
+        let Some(newline) = newline else {
+            return Ok(None);
+        };
+        let line = buf.split_to(newline + 1);
+        let line = String::from_utf8_lossy(&line[..newline]);
+        Ok(Some(Reply::Line(line.trim_end_matches('\r').to_owned())))
+    }
+}
+
+impl Encoder<String> for Codec {
+    type Error = Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), Error> {
+        buf.reserve(line.len() + 1);
+        buf.put(line.as_bytes());
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// Offset and length of the data of the IEEE 488.2 definite-length block
+/// `#<digits><length><data>`, `None` until the whole block is received
+fn block_header(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let header = |len: usize| String::from_utf8_lossy(&buf[..len.min(buf.len())]).into_owned();
+    let Some(&digits) = buf.get(1) else {
+        return Ok(None);
+    };
+    // Indefinite-length block `#0` is not supported, its data may contain the line feed
+    if !(b'1'..=b'9').contains(&digits) {
+        return Err(Error::Block(header(2)));
+    }
+    let offset = 2 + (digits - b'0') as usize;
+    let Some(length) = buf.get(2..offset) else {
+        return Ok(None);
+    };
+    let Some(length) = std::str::from_utf8(length)
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+    else {
+        return Err(Error::Block(header(offset)));
+    };
+    if length > MAX_BLOCK_LEN {
+        return Err(Error::Block(header(offset)));
+    }
+    match buf.len() >= offset + length {
+        true => Ok(Some((offset, length))),
+        false => Ok(None),
+    }
+}
+
+/// Definite-length block with the data, used by the simulation
+pub fn block(data: &[u8]) -> Vec<u8> {
+    let mut block = format!("#9{:09}", data.len()).into_bytes();
+    block.extend_from_slice(data);
+    block
+}
+
+/// Line based SCPI control connection, binary blocks are read by `query_block`
+pub struct Connection {
+    control: Framed<Box<dyn Stream>, Codec>,
+}
+
+impl Connection {
+    const COMMAND_REPLY_TIMEOUT: Duration = Duration::from_millis(3000);
+
+    pub async fn open(resource_addr: &str) -> Result<Self, Error> {
+        let tcp_stream = timeout(
+            Duration::from_millis(2000),
+            TcpStream::connect(resource_addr),
+        )
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+        let stream: Box<dyn Stream> = Box::new(tcp_stream);
+        let control = Framed::new(stream, Codec::default());
+
+        Ok(Self { control })
+    }
+
+    /// Instrument answering from the synthetic sources instead of the network
+    pub fn simulated(descriptor: &Descriptor, simulation: &simulation::Descriptor) -> Self {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(simulation::serve_scpi(
+            server,
+            simulation.clone(),
+            descriptor.clone(),
+        ));
+
+        let stream: Box<dyn Stream> = Box::new(client);
+        let control = Framed::new(stream, Codec::default());
+
+        Self { control }
+    }
+
+    pub async fn send(&mut self, payload: &str) -> Result<(), Error> {
+        self.control.send(payload.to_string()).await?;
+        Ok(())
+    }
+
+    /// Sends all the commands in order, the instrument does not reply to them
+    pub async fn send_all(&mut self, commands: &[String]) -> Result<(), Error> {
+        for command in commands {
+            self.send(command).await?;
+        }
+        Ok(())
+    }
+
+    /// Next reply, the empty lines left after the binary blocks are skipped
+    async fn recv(&mut self) -> Result<Reply, Error> {
+        loop {
+            match timeout(Self::COMMAND_REPLY_TIMEOUT, self.control.next()).await {
+                Err(_) => return Err(Error::Timeout),
+                Ok(None) => return Err(Error::Disconnected),
+                Ok(Some(Ok(Reply::Line(line)))) if line.trim().is_empty() => continue,
+                Ok(Some(reply)) => return reply,
+            }
+        }
+    }
+
+    /// Sends the query and waits for its reply
+    pub async fn query(&mut self, query: &str) -> Result<String, Error> {
+        self.send(query).await?;
+        match self.recv().await? {
+            Reply::Line(line) => Ok(line),
+            Reply::Block(data) => Err(Error::UnexpectedReply(format!(
+                "binary block of {} bytes",
+                data.len()
+            ))),
+        }
+    }
+
+    /// Reads the `SYST:ERR?` queue until it is empty
+    pub async fn error_queue(&mut self) -> Result<Vec<String>, Error> {
+        // The queue holds at most a few tens of errors, the limit avoids looping forever
+        const MAX_ERRORS: usize = 32;
+
+        let mut errors = Vec::new();
+        for _ in 0..MAX_ERRORS {
+            let reply = self.query("SYST:ERR?").await?;
+            let code = reply
+                .split(',')
+                .next()
+                .and_then(|code| code.trim().parse::<i32>().ok());
+            match code {
+                Some(0) => break,
+                Some(_) => errors.push(reply.trim().to_owned()),
+                None => return Err(Error::UnexpectedReply(reply)),
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Sends the query and waits for its binary block reply
+    pub async fn query_block(&mut self, query: &str) -> Result<Vec<u8>, Error> {
+        self.control.codec_mut().block = true;
+        self.send(query).await?;
+        let reply = self.recv().await;
+        self.control.codec_mut().block = false;
+        match reply? {
+            Reply::Block(data) => Ok(data),
+            Reply::Line(line) => Err(Error::UnexpectedReply(line)),
+        }
+    }
+}
+
+/// Number from the NR3 reply without the unit
+pub fn parse_number(reply: String, unit: &str) -> Result<f64, Error> {
+    let value = reply.trim();
+    let value = value.strip_suffix(unit).unwrap_or(value);
+    let Ok(value) = f64::from_str(value.trim()) else {
+        return Err(Error::Parse(reply));
+    };
+    match value.abs() >= INVALID {
+        true => Err(Error::Invalid(reply)),
+        false => Ok(value),
+    }
+}
+
+/// NR3 reply to the query, used by the simulation
+pub fn reply(value: Option<f64>, unit: &str) -> String {
+    match value {
+        Some(value) => format!("{value:.6E}{unit}"),
+        None => format!("{INVALID:E}"),
+    }
+}
+
+/// Any SCPI instrument, e.g. a DMM, set up and queried with the raw commands from the config
+pub struct Generic {
+    connection: Connection,
+}
+
+impl Generic {
+    pub fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+}
+
+#[async_trait]
+impl Instrument for Generic {
+    async fn init(&mut self, descriptor: &Descriptor) -> Result<(), Error> {
+        self.connection.send_all(&descriptor.init).await
+    }
+
+    async fn measure(&mut self, query: &Query) -> Result<f64, Error> {
+        let command = query
+            .command
+            .as_ref()
+            .expect("BUG: Generic measurement without command");
+        let reply = self.connection.query(command).await?;
+        parse_number(reply, &query.reply_unit)
+    }
+
+    async fn errors(&mut self) -> Result<Vec<String>, Error> {
+        self.connection.error_queue().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_binary_block() {
+        let mut codec = Codec { block: true };
+        let mut buf = BytesMut::from(&b"C1:WF DAT2,#9000000004"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"\x00\n\xff\x7f\n\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Reply::Block(vec![0x00, b'\n', 0xff, 0x7f]))
+        );
+        codec.block = false;
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Reply::Line(String::new()))
+        );
+
+        let mut buf = BytesMut::from(&b"#0\x00\x01\n"[..]);
+        codec.block = true;
+        assert!(matches!(codec.decode(&mut buf), Err(Error::Block(_))));
+        let mut buf = BytesMut::from(&b"#9100000001\x00"[..]);
+        assert!(matches!(codec.decode(&mut buf), Err(Error::Block(_))));
+    }
+
+    #[test]
+    fn parses_identity() {
+        let reply = "Siglent Technologies,SDS1204X-E,SDS1EBAX2R0000,8.2.6.1.37R2\n";
+        let identity = Identity::parse(reply.to_owned()).unwrap();
+        assert_eq!(identity.model, "SDS1204X-E");
+        assert_eq!(identity.firmware, "8.2.6.1.37R2");
+        assert!(Vendor::Siglent.matches(&identity));
+        assert!(!Vendor::Keysight.matches(&identity));
+        assert!(Vendor::Generic.matches(&identity));
+
+        assert!(Identity::parse("SDS1204X-E".to_owned()).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::measurement::unix_seconds;
+use crate::scope::{Coupling, Descriptor, Query};
+use crate::scpi::{self, Connection, Error, Instrument};
+use crate::waveform::Waveform;
+use async_trait::async_trait;
+use std::time::SystemTime;
+
+/// Horizontal divisions of the screen, the trigger is in the middle
+pub const DIVISIONS: f64 = 14.0;
+/// Waveform codes per vertical division
+pub const CODES_PER_DIV: f64 = 25.0;
+
+/// Siglent SDS scope, the measurements are read with the PAVA queries
+pub struct Siglent {
+    connection: Connection,
+}
+
+impl Siglent {
+    pub fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+
+    async fn setting(&mut self, query: &str, unit: &str) -> Result<f64, Error> {
+        let reply = self.connection.query(query).await?;
+        parse_setting(reply, unit)
+    }
+}
+
+#[async_trait]
+impl Instrument for Siglent {
+    async fn init(&mut self, descriptor: &Descriptor) -> Result<(), Error> {
+        self.connection.send_all(&init_commands(descriptor)).await?;
+        Ok(())
+    }
+
+    /// Queries the PAVA measurement and returns the value without the unit
+    async fn measure(&mut self, query: &Query) -> Result<f64, Error> {
+        let reply = self.connection.query(&command(query)).await?;
+
+        let Some(value) = reply.trim().split(',').nth(1) else {
+            return Err(Error::UnexpectedReply(reply));
+        };
+        if value == "****" {
+            return Err(Error::Invalid(reply));
+        }
+        scpi::parse_number(value.to_owned(), &query.reply_unit).map_err(|_| Error::Parse(reply))
+    }
+
+    /// Command error register holds only the last error, it is cleared by the read,
+    /// so at most one rejected command is reported per setup
+    async fn errors(&mut self) -> Result<Vec<String>, Error> {
+        let code = self.setting("CMR?", "").await?;
+        match code == 0.0 {
+            true => Ok(Vec::new()),
+            false => Ok(vec![format!("command error {code}")]),
+        }
+    }
+
+    async fn waveform(&mut self, channel: u8, points: usize) -> Result<Waveform, Error> {
+        let name = channel_name(channel);
+        let acquired = SystemTime::now();
+        let volts_per_div = self.setting(&format!("{name}:VDIV?"), "V").await?;
+        let offset = self.setting(&format!("{name}:OFST?"), "V").await?;
+        let time_per_div = self.setting("TDIV?", "S").await?;
+        let sample_rate = self.setting("SARA?", "Sa/s").await?;
+        let samples = self.setting(&format!("SANU? {name}"), "pts").await?;
+
+        // Every n-th sample is transferred to get at most the requested points
+        let sparsing = (samples / points as f64).ceil().max(1.0);
+        self.connection
+            .send(&format!("WFSU SP,{sparsing},NP,{points},FP,0"))
+            .await?;
+        let data = self
+            .connection
+            .query_block(&format!("{name}:WF? DAT2"))
+            .await?;
+
+        Ok(Waveform {
+            timestamp: unix_seconds(acquired),
+            channel,
+            start: -time_per_div * DIVISIONS / 2.0,
+            interval: sparsing / sample_rate,
+            values: data
+                .iter()
+                .map(|&code| volts(code, volts_per_div, offset))
+                .collect(),
+        })
+    }
+}
+
+/// Value of the setting, e.g. `C1:VDIV 5.00E+00V` is 5 with the unit V,
+/// the sample rate and memory depth have SI prefixes, e.g. `SARA 1.00GSa/s`
+fn parse_setting(reply: String, unit: &str) -> Result<f64, Error> {
+    let value = reply.split_whitespace().last().unwrap_or_default();
+    let value = value.strip_suffix(unit).unwrap_or(value);
+    let (value, multiplier) = match value.chars().last() {
+        Some('G') => (&value[..value.len() - 1], 1e9),
+        Some('M') => (&value[..value.len() - 1], 1e6),
+        Some('k') => (&value[..value.len() - 1], 1e3),
+        _ => (value, 1.0),
+    };
+    match scpi::parse_number(value.to_owned(), "") {
+        Ok(value) => Ok(value * multiplier),
+        Err(_) => Err(Error::Parse(reply)),
+    }
+}
+
+/// Codes are signed bytes, 0 is the middle of the screen
+fn volts(code: u8, volts_per_div: f64, offset: f64) -> f64 {
+    code as i8 as f64 * volts_per_div / CODES_PER_DIV - offset
+}
+
+/// Setting with the SI prefix as the scope replies it, used by the simulation
+pub fn prefixed(value: f64, unit: &str) -> String {
+    let (multiplier, prefix) = match value.abs() {
+        v if v >= 1e9 => (1e9, "G"),
+        v if v >= 1e6 => (1e6, "M"),
+        v if v >= 1e3 => (1e3, "k"),
+        _ => (1.0, ""),
+    };
+    format!("{:.2}{prefix}{unit}", value / multiplier)
+}
+
+fn channel_name(channel: u8) -> String {
+    format!("C{channel}")
+}
+
+fn coupling(coupling: Coupling) -> &'static str {
+    match coupling {
+        Coupling::Dc => "D1M",
+        Coupling::Ac => "A1M",
+        Coupling::Dc50 => "D50",
+        Coupling::Ac50 => "A50",
+        Coupling::Gnd => "GND",
+    }
+}
+
+/// SCPI commands setting up the scope from the reset state, followed by the raw ones
+pub fn init_commands(descriptor: &Descriptor) -> Vec<String> {
+    let mut commands = vec![
+        "*RST".to_owned(),
+        format!("TDIV {}S", descriptor.time_per_div_s),
+    ];
+    for channel in &descriptor.channels {
+        let name = channel_name(channel.channel);
+        commands.push(format!("{name}:ATTN {}", channel.attenuation));
+        commands.push(format!("{name}:VDIV {}V", channel.volts_per_div));
+        commands.push(format!("{name}:OFST {}V", channel.offset_v));
+        commands.push(format!("{name}:CPL {}", coupling(channel.coupling)));
+        let bandwidth_limit = match channel.bandwidth_limit {
+            true => "ON",
+            false => "OFF",
+        };
+        commands.push(format!("BWL {name},{bandwidth_limit}"));
+    }
+    commands.extend(descriptor.init.iter().cloned());
+    commands
+}
+
+fn parts(query: &Query) -> (String, String) {
+    let channel = query
+        .channel
+        .expect("BUG: Scope measurement without channel");
+    let parameter = query
+        .parameter
+        .as_ref()
+        .expect("BUG: Scope measurement without parameter");
+    (channel_name(channel), parameter.to_uppercase())
+}
+
+pub fn command(query: &Query) -> String {
+    let (channel, parameter) = parts(query);
+    format!("{channel}:PAVA? {parameter}")
+}
+
+/// Reply of the scope to the PAVA query, used by the simulation
+pub fn reply(query: &Query, value: Option<f64>) -> String {
+    let (channel, parameter) = parts(query);
+    match value {
+        Some(value) => format!("{channel}:PAVA {parameter},{value:.6E}{}", query.reply_unit),
+        None => format!("{channel}:PAVA {parameter},****"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings() {
+        let setting = |reply: &str, unit| parse_setting(reply.to_owned(), unit).unwrap();
+        assert_eq!(setting("C1:VDIV 5.00E+00V", "V"), 5.0);
+        assert_eq!(setting("TDIV 1.00E-02S", "S"), 0.01);
+        assert_eq!(setting("SARA 1.00GSa/s", "Sa/s"), 1e9);
+        assert_eq!(setting("SARA 500.00kSa/s", "Sa/s"), 5e5);
+        assert_eq!(setting("SANU 14.00Mpts", "pts"), 14e6);
+        assert!(parse_setting("SANU ****".to_owned(), "pts").is_err());
+        assert_eq!(prefixed(1e9, "Sa/s"), "1.00GSa/s");
+        assert_eq!(prefixed(14000.0, "pts"), "14.00kpts");
+    }
+
+    #[test]
+    fn converts_codes_to_volts() {
+        // C1:VDIV 5.00E+00V and C1:OFST -1.50E+01V, the screen middle is at 15 V
+        let volts_per_div = parse_setting("C1:VDIV 5.00E+00V".to_owned(), "V").unwrap();
+        let offset = parse_setting("C1:OFST -1.50E+01V".to_owned(), "V").unwrap();
+        assert_eq!(volts(0x00, volts_per_div, offset), 15.0);
+        assert_eq!(volts(0x19, volts_per_div, offset), 20.0);
+        assert_eq!(volts(0xE7, volts_per_div, offset), 10.0);
+    }
+
+    #[test]
+    fn generates_init_commands() {
+        let descriptor: Descriptor = toml::from_str(
+            r#"
+            address = "10.34.0.65"
+            port = 5025
+
+            [[channels]]
+            channel = 1
+            attenuation = 10
+            volts_per_div = 5.0
+            offset_v = -15.0
+
+            [[channels]]
+            channel = 2
+            volts_per_div = 0.5
+            coupling = "ac50"
+            bandwidth_limit = true
+            "#,
+        )
+        .unwrap();
+        descriptor.validate().unwrap();
+
+        assert_eq!(
+            init_commands(&descriptor),
+            [
+                "*RST",
+                "TDIV 0.01S",
+                "C1:ATTN 10",
+                "C1:VDIV 5V",
+                "C1:OFST -15V",
+                "C1:CPL D1M",
+                "BWL C1,OFF",
+                "C2:ATTN 1",
+                "C2:VDIV 0.5V",
+                "C2:OFST 0V",
+                "C2:CPL A50",
+                "BWL C2,ON",
+            ]
+        );
+    }
+}
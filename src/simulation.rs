@@ -0,0 +1,532 @@
+use crate::max31856::register;
+use crate::scope::{self, Query, Vendor};
+use crate::spi::Device;
+use crate::{scpi, siglent};
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+/// Waveforms of the synthetic sources used instead of the real hardware
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct Descriptor {
+    pub sensors: Waveform,
+    pub ambient_temperature: Waveform,
+    /// Reported values of the instrument measurements by the instrument and the measurement name,
+    /// measurements without a waveform fail
+    pub instruments: BTreeMap<String, BTreeMap<String, Waveform>>,
+    pub power: Waveform,
+}
+
+impl Descriptor {
+    pub fn validate(&self) -> Result<()> {
+        let named = [
+            ("sensors", &self.sensors),
+            ("ambient_temperature", &self.ambient_temperature),
+            ("power", &self.power),
+        ];
+        for (name, waveform) in named {
+            waveform.validate(name)?;
+        }
+        for (instrument, waveforms) in &self.instruments {
+            for (name, waveform) in waveforms {
+                waveform.validate(&format!("instruments.{instrument}.{name}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Self {
+            sensors: Waveform::new(Shape::Sine {
+                offset: 60.0,
+                amplitude: 20.0,
+                period: 300.0,
+            }),
+            ambient_temperature: Waveform::new(Shape::Constant { value: 24.0 }),
+            instruments: BTreeMap::from([(
+                "scope".to_owned(),
+                BTreeMap::from([
+                    (
+                        "psu_voltage".to_owned(),
+                        Waveform::new(Shape::Constant { value: 12.0 }),
+                    ),
+                    (
+                        "fan_rpm".to_owned(),
+                        Waveform::new(Shape::Step {
+                            before: 1200.0,
+                            after: 2400.0,
+                            at: 60.0,
+                        }),
+                    ),
+                ]),
+            )]),
+            power: Waveform::new(Shape::Ramp {
+                start: 10.0,
+                end: 60.0,
+                period: 600.0,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum Shape {
+    Constant {
+        value: f64,
+    },
+    /// Sawtooth going from `start` to `end`, repeated every `period` seconds
+    Ramp {
+        start: f64,
+        end: f64,
+        period: f64,
+    },
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period: f64,
+    },
+    /// Jumps from `before` to `after` at `at` seconds after the start
+    Step {
+        before: f64,
+        after: f64,
+        at: f64,
+    },
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Waveform {
+    #[serde(flatten)]
+    pub shape: Shape,
+    /// Amplitude of the uniform noise added to every sample
+    #[serde(default)]
+    pub noise: f64,
+    /// Probability in range 0.0 - 1.0 that the sample is lost
+    #[serde(default)]
+    pub dropout: f64,
+}
+
+impl Waveform {
+    pub fn new(shape: Shape) -> Self {
+        Self {
+            shape,
+            noise: 0.0,
+            dropout: 0.0,
+        }
+    }
+
+    /// Catches values which would make the samples NaN or panic the random generator
+    fn validate(&self, name: &str) -> Result<()> {
+        if let Shape::Ramp { period, .. } | Shape::Sine { period, .. } = self.shape {
+            if !(period > 0.0 && period.is_finite()) {
+                bail!("Period of simulated {name} must be positive");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.dropout) {
+            bail!("Dropout of simulated {name} must be in range 0.0 - 1.0");
+        }
+        if !(self.noise >= 0.0 && self.noise.is_finite()) {
+            bail!("Noise of simulated {name} must be a positive number");
+        }
+        Ok(())
+    }
+}
+
+/// Waveform running since its creation
+pub struct Signal {
+    waveform: Waveform,
+    start: Instant,
+}
+
+impl Signal {
+    pub fn new(waveform: &Waveform) -> Self {
+        Self {
+            waveform: waveform.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Current value of the signal, `None` when the sample is dropped
+    pub fn sample(&mut self) -> Option<f64> {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.waveform.dropout) {
+            return None;
+        }
+
+        let t = self.start.elapsed().as_secs_f64();
+        let value = match self.waveform.shape {
+            Shape::Constant { value } => value,
+            Shape::Ramp { start, end, period } => start + (end - start) * (t % period) / period,
+            Shape::Sine {
+                offset,
+                amplitude,
+                period,
+            } => offset + amplitude * f64::sin(2.0 * PI * t / period),
+            Shape::Step { before, after, at } => match t < at {
+                true => before,
+                false => after,
+            },
+        };
+
+        let noise = match self.waveform.noise > 0.0 {
+            true => rng.gen_range(-self.waveform.noise..self.waveform.noise),
+            false => 0.0,
+        };
+        Some(value + noise)
+    }
+}
+
+/// MAX6675 converter with a thermocouple following the signal
+pub struct MAX6675 {
+    signal: Signal,
+}
+
+impl MAX6675 {
+    pub fn new(waveform: &Waveform) -> Self {
+        Self {
+            signal: Signal::new(waveform),
+        }
+    }
+}
+
+impl Device for MAX6675 {
+    fn transfer(&mut self, _tx_buf: &[u8], rx_buf: &mut [u8]) {
+        let frame: u16 = match self.signal.sample() {
+            // 12-bit temperature with 0.25 ˚C resolution in bits D14 - D3
+            Some(temp) => ((temp / 0.25).round().clamp(0.0, 4095.0) as u16) << 3,
+            // Dropped sample looks like disconnected thermocouple
+            None => 0x0004,
+        };
+        for (byte, value) in rx_buf.iter_mut().zip(frame.to_be_bytes()) {
+            *byte = value;
+        }
+    }
+}
+
+/// MAX31855 converter with the thermocouple and cold junction following the signals
+pub struct MAX31855 {
+    thermocouple: Signal,
+    cold_junction: Signal,
+}
+
+impl MAX31855 {
+    pub fn new(thermocouple: &Waveform, cold_junction: &Waveform) -> Self {
+        Self {
+            thermocouple: Signal::new(thermocouple),
+            cold_junction: Signal::new(cold_junction),
+        }
+    }
+}
+
+impl Device for MAX31855 {
+    fn transfer(&mut self, _tx_buf: &[u8], rx_buf: &mut [u8]) {
+        let cold_junction = self.cold_junction.sample().unwrap_or(25.0);
+        // 12-bit signed cold junction with 0.0625 ˚C resolution in bits D15 - D4
+        let cold_junction = (((cold_junction / 0.0625).round() as i16) << 4) as u16 as u32;
+        let frame: u32 = match self.thermocouple.sample() {
+            // 14-bit signed temperature with 0.25 ˚C resolution in bits D31 - D18
+            Some(temp) => {
+                let temp = (((temp / 0.25).round() as i16) << 2) as u16 as u32;
+                temp << 16 | cold_junction
+            }
+            // Dropped sample looks like disconnected thermocouple
+            None => 0x0001_0001 | cold_junction,
+        };
+        for (byte, value) in rx_buf.iter_mut().zip(frame.to_be_bytes()) {
+            *byte = value;
+        }
+    }
+}
+
+/// MAX31856 converter with a register map, the measurement is refreshed on every read
+pub struct MAX31856 {
+    thermocouple: Signal,
+    cold_junction: Signal,
+    registers: [u8; 16],
+}
+
+impl MAX31856 {
+    const SR: usize = 0x0F;
+
+    pub fn new(thermocouple: &Waveform, cold_junction: &Waveform) -> Self {
+        Self {
+            thermocouple: Signal::new(thermocouple),
+            cold_junction: Signal::new(cold_junction),
+            registers: [0; 16],
+        }
+    }
+
+    fn convert(&mut self) {
+        let cold_junction = self.cold_junction.sample().unwrap_or(25.0);
+        // 14-bit signed cold junction with 0.015625 ˚C resolution
+        let cold_junction = ((cold_junction / 0.015625).round() as i16) << 2;
+        let cjt = register::CJTH as usize;
+        self.registers[cjt..cjt + 2].copy_from_slice(&cold_junction.to_be_bytes());
+
+        match self.thermocouple.sample() {
+            Some(temp) => {
+                // 19-bit signed temperature with 0.0078125 ˚C resolution
+                let temp = ((temp / 0.0078125).round() as i32) << 13;
+                self.registers[cjt + 2..cjt + 5].copy_from_slice(&temp.to_be_bytes()[..3]);
+                self.registers[Self::SR] = 0x00;
+            }
+            // Dropped sample looks like disconnected thermocouple
+            None => self.registers[Self::SR] = 0x01,
+        }
+    }
+}
+
+impl Device for MAX31856 {
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) {
+        let Some(address) = tx_buf.first() else {
+            return;
+        };
+
+        let start = (address & !register::WRITE) as usize;
+        let len = tx_buf.len().min(self.registers.len() - start + 1);
+        if address & register::WRITE != 0 {
+            self.registers[start..start + len - 1].copy_from_slice(&tx_buf[1..len]);
+            return;
+        }
+
+        if start == register::CJTH as usize {
+            self.convert();
+        }
+        rx_buf[1..len].copy_from_slice(&self.registers[start..start + len - 1]);
+    }
+}
+
+/// Content of the DS18B20 `w1_slave` file, dropped sample fails the CRC check
+pub fn w1_slave(temperature: Option<f64>) -> String {
+    const SCRATCHPAD: &str = "72 01 4b 46 7f ff 0e 10 57";
+    match temperature {
+        Some(temp) => {
+            let millis = (temp * 1000.0).round() as i64;
+            format!("{SCRATCHPAD} : crc=57 YES\n{SCRATCHPAD} t={millis}\n")
+        }
+        None => format!("{SCRATCHPAD} : crc=57 NO\n{SCRATCHPAD} t=85000\n"),
+    }
+}
+
+/// Content of the Netio `netio.json`, the power is split evenly between the outputs
+pub fn netio_json(outputs: &[u64], power: Option<f64>) -> String {
+    let Some(power) = power else {
+        return json!({ "Outputs": [] }).to_string();
+    };
+
+    let load = power / outputs.len().max(1) as f64;
+    let outputs: Vec<_> = outputs
+        .iter()
+        .map(|id| json!({ "ID": id, "State": 1, "Load": load.round() }))
+        .collect();
+    json!({ "Outputs": outputs }).to_string()
+}
+
+/// Answers the SCPI queries of the scope, setup commands are silently accepted
+pub async fn serve_scpi(stream: DuplexStream, descriptor: Descriptor, scope: scope::Descriptor) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut commands = FramedRead::new(reader, LinesCodec::new());
+    let waveforms = descriptor.instruments.get(&scope.name);
+    let mut signals: BTreeMap<String, (Query, Option<Signal>)> = scope
+        .measurements
+        .iter()
+        .map(|query| {
+            let signal = waveforms
+                .and_then(|waveforms| waveforms.get(&query.name))
+                .map(Signal::new);
+            (scope.vendor.command(query), (query.clone(), signal))
+        })
+        .collect();
+    let mut transfer = Transfer::default();
+
+    while let Some(Ok(command)) = commands.next().await {
+        let command = command.trim();
+        let mut reply = match signals.get_mut(command) {
+            Some((query, signal)) => {
+                // Waveform is the reported value, the scope replies with the value before conversion
+                let value = signal
+                    .as_mut()
+                    .and_then(Signal::sample)
+                    .map(|value| query.invert(value));
+                scope.vendor.reply(query, value).into_bytes()
+            }
+            None => match status(scope.vendor, command) {
+                Some(reply) => reply.into_bytes(),
+                None => match transfer.reply(&scope, command) {
+                    Some(reply) => reply,
+                    None => {
+                        debug!("Simulated scope accepted {command}");
+                        continue;
+                    }
+                },
+            },
+        };
+
+        reply.push(b'\n');
+        if writer.write_all(&reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Reply to the identification and the error queries, all the commands succeed
+fn status(vendor: Vendor, command: &str) -> Option<String> {
+    let reply = match command {
+        "*IDN?" => match vendor {
+            Vendor::Siglent => "Siglent Technologies,SDS1204X-E,SIMULATED,8.2.6.1.37R2",
+            Vendor::Rigol => "RIGOL TECHNOLOGIES,DS1104Z,SIMULATED,00.04.05.SP2",
+            Vendor::Keysight => "KEYSIGHT TECHNOLOGIES,DSOX1204G,SIMULATED,02.12.2021071625",
+            Vendor::Generic => "KEITHLEY INSTRUMENTS INC.,MODEL 2000,SIMULATED,A20 /A02",
+        },
+        "CMR?" => "CMR 0",
+        "SYST:ERR?" => "+0,\"No error\"",
+        _ => return None,
+    };
+    Some(reply.to_owned())
+}
+
+/// Waveform transfer setup of the simulated scope, set by the commands before the transfer
+struct Transfer {
+    channel: u8,
+    points: usize,
+    sparsing: usize,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self {
+            channel: 1,
+            points: 1000,
+            sparsing: 1,
+        }
+    }
+}
+
+impl Transfer {
+    /// Samples in the memory of the simulated Siglent scope
+    const SAMPLES: usize = 14000;
+
+    /// Reply to the waveform transfer queries, `None` for the other commands
+    fn reply(&mut self, scope: &scope::Descriptor, command: &str) -> Option<Vec<u8>> {
+        match scope.vendor {
+            Vendor::Siglent => self.siglent(scope, command),
+            Vendor::Rigol | Vendor::Keysight => self.rigol(scope, command),
+            Vendor::Generic => None,
+        }
+    }
+
+    fn siglent(&mut self, scope: &scope::Descriptor, command: &str) -> Option<Vec<u8>> {
+        let time_per_div = scope.time_per_div_s;
+        let reply = match command {
+            "TDIV?" => format!("TDIV {time_per_div:E}S"),
+            "SARA?" => {
+                let sample_rate = Self::SAMPLES as f64 / (siglent::DIVISIONS * time_per_div);
+                format!("SARA {}", siglent::prefixed(sample_rate, "Sa/s"))
+            }
+            _ if command.starts_with("SANU? ") => {
+                format!("SANU {}", siglent::prefixed(Self::SAMPLES as f64, "pts"))
+            }
+            _ if command.starts_with("WFSU ") => {
+                let setup: Vec<&str> = command["WFSU ".len()..].split(',').collect();
+                if let ["SP", sparsing, "NP", points, ..] = setup[..] {
+                    self.sparsing = sparsing.parse().unwrap_or(1).max(1);
+                    self.points = points.parse().unwrap_or(self.points);
+                }
+                return None;
+            }
+            _ => {
+                let (name, query) = command.split_once(':')?;
+                let channel = name.strip_prefix('C')?.parse().ok()?;
+                let (volts_per_div, offset) = channel_setup(scope, channel);
+                match query {
+                    "VDIV?" => format!("{name}:VDIV {volts_per_div:E}V"),
+                    "OFST?" => format!("{name}:OFST {offset:E}V"),
+                    "WF? DAT2" => {
+                        let points = self.points.min(Self::SAMPLES.div_ceil(self.sparsing));
+                        let data: Vec<u8> = codes(points).iter().map(|&code| code as u8).collect();
+                        let mut reply = format!("{name}:WF DAT2,").into_bytes();
+                        reply.extend(scpi::block(&data));
+                        // Siglent terminates the block with 2 line feeds
+                        reply.push(b'\n');
+                        return Some(reply);
+                    }
+                    _ => return None,
+                }
+            }
+        };
+        Some(reply.into_bytes())
+    }
+
+    fn rigol(&mut self, scope: &scope::Descriptor, command: &str) -> Option<Vec<u8>> {
+        if let Some(channel) = command.strip_prefix(":WAVeform:SOURce CHANnel") {
+            self.channel = channel.parse().unwrap_or(self.channel);
+            return None;
+        }
+        if let Some(points) = command.strip_prefix(":WAVeform:POINts ") {
+            self.points = points.parse().unwrap_or(self.points);
+            return None;
+        }
+
+        let keysight = scope.vendor == Vendor::Keysight;
+        let (volts_per_div, offset) = channel_setup(scope, self.channel);
+        let y_increment = volts_per_div / siglent::CODES_PER_DIV;
+        // Rigol origin is in codes, Keysight one is the voltage in the middle of the screen
+        let (y_origin, y_reference) = match keysight {
+            true => (-offset, 128.0),
+            false => (offset / y_increment, 127.0),
+        };
+        let reply = match command {
+            ":WAVeform:PREamble?" => {
+                let divisions = match keysight {
+                    true => 10.0,
+                    false => 12.0,
+                };
+                let x_increment = divisions * scope.time_per_div_s / self.points as f64;
+                let x_origin = -divisions * scope.time_per_div_s / 2.0;
+                format!(
+                    "0,0,{},1,{x_increment:E},{x_origin:E},0,{y_increment:E},{y_origin:E},{y_reference}",
+                    self.points
+                )
+            }
+            ":WAVeform:DATA?" => {
+                let data: Vec<u8> = codes(self.points)
+                    .iter()
+                    .map(|&code| (code as f64 + y_reference) as u8)
+                    .collect();
+                return Some(scpi::block(&data));
+            }
+            _ => return None,
+        };
+        Some(reply.into_bytes())
+    }
+}
+
+/// V/div and offset of the configured channel, the offset moves the trace like on Siglent
+fn channel_setup(scope: &scope::Descriptor, channel: u8) -> (f64, f64) {
+    scope
+        .channels
+        .iter()
+        .find(|setup| setup.channel == channel)
+        .map_or((1.0, 0.0), |setup| (setup.volts_per_div, setup.offset_v))
+}
+
+/// Signed codes of 5 sine periods on the screen, 2 divisions high
+fn codes(points: usize) -> Vec<i8> {
+    (0..points)
+        .map(|index| {
+            let phase = 2.0 * PI * 5.0 * index as f64 / points as f64;
+            (2.0 * siglent::CODES_PER_DIV * f64::sin(phase)).round() as i8
+        })
+        .collect()
+}
@@ -1,31 +1,239 @@
-use anyhow::Result;
-use spidev::{SpiModeFlags, Spidev, SpidevOptions};
-use std::io::Read;
+use rppal::gpio::{self, Gpio, OutputPin};
+use spidev::{Spidev, SpidevOptions, SpidevTransfer};
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
 
-const NUM_RESPONSE_BYTES: usize = 2;
+pub use spidev::SpiModeFlags as Mode;
+
+/// SPI bus shared by all sensors, chip select decides who is talking
+pub type SharedBus = Arc<Mutex<dyn Bus>>;
+
+pub trait Bus: Send {
+    /// Clock polarity and phase of the following transfers
+    fn set_mode(&mut self, mode: Mode) -> io::Result<()>;
+
+    /// Full duplex transfer with the currently selected device,
+    /// both buffers have the same length
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> io::Result<()>;
+}
+
+pub trait ChipSelect: Send {
+    fn select(&mut self);
+    fn deselect(&mut self);
+}
 
 pub struct Spi {
     inner: Spidev,
+    mode: Mode,
 }
 
 impl Spi {
-    pub fn open() -> Self {
-        let mut inner = Spidev::open("/dev/spidev0.0").expect("BUG: Failed to open SPI device");
+    /// Fails when the SPI is not enabled, e.g. by `dtparam=spi=on`
+    pub fn open() -> io::Result<Self> {
+        let mut inner = Spidev::open("/dev/spidev0.0")?;
         let options = SpidevOptions::new()
             .bits_per_word(8)
             .max_speed_hz(500_000)
-            .mode(SpiModeFlags::SPI_MODE_0)
+            .mode(Mode::SPI_MODE_0)
             .build();
-        inner
-            .configure(&options)
-            .expect("BUG: failed to configure spi");
+        inner.configure(&options)?;
+
+        Ok(Self {
+            inner,
+            mode: Mode::SPI_MODE_0,
+        })
+    }
+}
+
+impl Bus for Spi {
+    fn set_mode(&mut self, mode: Mode) -> io::Result<()> {
+        // Reconfiguring is a syscall, skip it while the same chip type is talking
+        if mode != self.mode {
+            self.inner
+                .configure(&SpidevOptions::new().mode(mode).build())?;
+            self.mode = mode;
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> io::Result<()> {
+        let mut transfer = SpidevTransfer::read_write(tx_buf, rx_buf);
+        self.inner.transfer(&mut transfer)?;
+        Ok(())
+    }
+}
+
+/// Device on the shared bus with its own chip select and SPI mode
+pub struct SpiDevice {
+    bus: SharedBus,
+    cs: Box<dyn ChipSelect>,
+    mode: Mode,
+}
+
+impl SpiDevice {
+    pub fn new(bus: SharedBus, cs: Box<dyn ChipSelect>, mode: Mode) -> Self {
+        Self { bus, cs, mode }
+    }
+
+    /// Clocks in `rx_buf.len()` bytes while MOSI is held low
+    pub fn read(&mut self, rx_buf: &mut [u8]) -> io::Result<()> {
+        let tx_buf = vec![0_u8; rx_buf.len()];
+        self.transfer(&tx_buf, rx_buf)
+    }
+
+    pub fn write(&mut self, tx_buf: &[u8]) -> io::Result<()> {
+        let mut rx_buf = vec![0_u8; tx_buf.len()];
+        self.transfer(tx_buf, &mut rx_buf)
+    }
+
+    pub fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> io::Result<()> {
+        // The lock is held over the reconfiguration, devices with another mode
+        // share the bus
+        let mut bus = self.bus.lock().expect("BUG: Failed to acquire SPI device");
+        bus.set_mode(self.mode)?;
+
+        // Custom CS implementation
+        self.cs.select();
+        let result = bus.transfer(tx_buf, rx_buf);
+        self.cs.deselect();
+        result
+    }
+}
+
+/// Chip select driven by a GPIO pin, the hardware CS of the SPI is not used
+pub struct GpioChipSelect {
+    pin: OutputPin,
+}
+
+impl GpioChipSelect {
+    pub fn new(cs_pin: usize) -> Result<Self, gpio::Error> {
+        let mut pin = Gpio::new()?.get(cs_pin as u8)?.into_output();
+
+        pin.set_high();
+
+        Ok(Self { pin })
+    }
+}
+
+impl ChipSelect for GpioChipSelect {
+    fn select(&mut self) {
+        self.pin.set_low();
+    }
+
+    fn deselect(&mut self) {
+        self.pin.set_high();
+    }
+}
+
+/// Peripheral attached to the `SimulatedBus`
+pub trait Device: Send {
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]);
+}
+
+/// Replays scripted 16-bit frames, the last one is repeated when the script runs out
+#[cfg(test)]
+pub struct Script {
+    frames: VecDeque<u16>,
+}
+
+#[cfg(test)]
+impl Script {
+    pub fn new(frames: impl IntoIterator<Item = u16>) -> Self {
+        Self {
+            frames: frames.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Device for Script {
+    fn transfer(&mut self, _tx_buf: &[u8], rx_buf: &mut [u8]) {
+        let frame = match self.frames.len() {
+            0 => 0x0000,
+            1 => self.frames[0],
+            _ => self.frames.pop_front().expect("BUG: Script is empty"),
+        };
+        for (byte, value) in rx_buf.iter_mut().zip(frame.to_be_bytes()) {
+            *byte = value;
+        }
+    }
+}
+
+#[derive(Default)]
+struct SimulatedState {
+    devices: BTreeMap<usize, Box<dyn Device>>,
+    selected: Option<usize>,
+}
+
+/// In-memory SPI bus, devices are attached to the chip select pins
+#[derive(Clone, Default)]
+pub struct SimulatedBus {
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+impl SimulatedBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn attach(&self, cs_pin: usize, device: impl Device + 'static) {
+        self.state
+            .lock()
+            .expect("BUG: Failed to acquire simulated bus lock")
+            .devices
+            .insert(cs_pin, Box::new(device));
+    }
+
+    pub fn chip_select(&self, cs_pin: usize) -> SimulatedChipSelect {
+        SimulatedChipSelect {
+            state: self.state.clone(),
+            cs_pin,
+        }
+    }
+}
+
+impl Bus for SimulatedBus {
+    fn set_mode(&mut self, _mode: Mode) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> io::Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("BUG: Failed to acquire simulated bus lock");
+
+        // Nobody drives MISO without a selected device, zeros are read
+        rx_buf.fill(0x00);
+        if let Some(cs_pin) = state.selected {
+            if let Some(device) = state.devices.get_mut(&cs_pin) {
+                device.transfer(tx_buf, rx_buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct SimulatedChipSelect {
+    state: Arc<Mutex<SimulatedState>>,
+    cs_pin: usize,
+}
 
-        Self { inner }
+impl ChipSelect for SimulatedChipSelect {
+    fn select(&mut self) {
+        self.state
+            .lock()
+            .expect("BUG: Failed to acquire simulated bus lock")
+            .selected = Some(self.cs_pin);
     }
 
-    pub fn read(&mut self) -> Result<[u8; NUM_RESPONSE_BYTES]> {
-        let mut rx_buf = [0_u8; NUM_RESPONSE_BYTES];
-        self.inner.read_exact(&mut rx_buf)?;
-        Ok(rx_buf)
+    fn deselect(&mut self) {
+        self.state
+            .lock()
+            .expect("BUG: Failed to acquire simulated bus lock")
+            .selected = None;
     }
 }
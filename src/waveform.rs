@@ -0,0 +1,174 @@
+use crate::scpi::Error;
+use serde::Serialize;
+use std::fmt::Write;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum Format {
+    Json,
+    Csv,
+    Svg,
+}
+
+/// Waveform of one channel captured by the scope
+#[derive(Clone, Debug, Serialize)]
+pub struct Waveform {
+    /// Unix time of the capture
+    pub timestamp: f64,
+    pub channel: u8,
+    /// Time of the first point in s, relative to the trigger
+    pub start: f64,
+    /// Time between the points in s
+    pub interval: f64,
+    /// Voltages of the points in V
+    pub values: Vec<f64>,
+}
+
+impl Waveform {
+    pub const DEFAULT_POINTS: usize = 1000;
+    pub const MAX_POINTS: usize = 100_000;
+
+    fn time(&self, index: usize) -> f64 {
+        self.start + index as f64 * self.interval
+    }
+
+    pub fn csv(&self) -> String {
+        let mut csv = String::from("time_s,voltage_v\n");
+        for (index, value) in self.values.iter().enumerate() {
+            writeln!(csv, "{:.9},{value}", self.time(index)).expect("BUG: Failed to write CSV");
+        }
+        csv
+    }
+
+    /// Trace on the 10 x 8 division grid, scaled to fit the values
+    pub fn svg(&self) -> String {
+        const WIDTH: f64 = 800.0;
+        const HEIGHT: f64 = 400.0;
+
+        let (min, max) = self
+            .values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+                (min.min(*value), max.max(*value))
+            });
+        // Flat trace is drawn in the middle
+        let (bottom, span) = match max > min {
+            true => (min, max - min),
+            false => (min - 0.5, 1.0),
+        };
+        let last = self.values.len().saturating_sub(1).max(1) as f64;
+        let points: Vec<String> = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let x = index as f64 / last * WIDTH;
+                let y = HEIGHT * (0.95 - 0.9 * (value - bottom) / span);
+                format!("{x:.1},{y:.1}")
+            })
+            .collect();
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}">"#
+        );
+        svg.push_str(r##"<rect width="100%" height="100%" fill="#111"/>"##);
+        for division in 1..10 {
+            let x = WIDTH * division as f64 / 10.0;
+            write!(
+                svg,
+                r##"<line x1="{x}" y1="0" x2="{x}" y2="{HEIGHT}" stroke="#333"/>"##
+            )
+            .expect("BUG: Failed to write SVG");
+        }
+        for division in 1..8 {
+            let y = HEIGHT * division as f64 / 8.0;
+            write!(
+                svg,
+                r##"<line x1="0" y1="{y}" x2="{WIDTH}" y2="{y}" stroke="#333"/>"##
+            )
+            .expect("BUG: Failed to write SVG");
+        }
+        write!(
+            svg,
+            r##"<polyline points="{}" fill="none" stroke="#fd0" stroke-width="1.5"/>"##,
+            points.join(" ")
+        )
+        .expect("BUG: Failed to write SVG");
+        let duration = self.interval * self.values.len() as f64;
+        write!(
+            svg,
+            r##"<g fill="#ccc" font-family="monospace" font-size="14"><text x="8" y="20">C{} max {max:.4} V</text><text x="8" y="{}">min {min:.4} V, {duration:.3e} s</text></g>"##,
+            self.channel,
+            HEIGHT - 8.0
+        )
+        .expect("BUG: Failed to write SVG");
+        svg.push_str("</svg>");
+        svg
+    }
+}
+
+/// Waveform capture asked by the API, served by the scope task which owns the connection
+pub struct Request {
+    pub channel: u8,
+    pub points: usize,
+    pub reply: oneshot::Sender<Result<Waveform, Error>>,
+}
+
+/// Asks the scope task for the waveforms
+#[derive(Clone)]
+pub struct Capture {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Capture {
+    /// The scope may be reconnecting or busy with the measurements
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new() -> (Self, mpsc::Receiver<Request>) {
+        let (requests, receiver) = mpsc::channel(4);
+        (Self { requests }, receiver)
+    }
+
+    pub async fn waveform(&self, channel: u8, points: usize) -> Result<Waveform, Error> {
+        let (reply, receiver) = oneshot::channel();
+        let request = Request {
+            channel,
+            points,
+            reply,
+        };
+        let capture = async {
+            self.requests
+                .send(request)
+                .await
+                .map_err(|_| Error::Disconnected)?;
+            receiver.await.map_err(|_| Error::Disconnected)?
+        };
+        timeout(Self::TIMEOUT, capture)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_waveform() {
+        let waveform = Waveform {
+            timestamp: 1700000000.0,
+            channel: 1,
+            start: -0.5,
+            interval: 0.25,
+            values: vec![1.0, 2.0, 1.5],
+        };
+        assert_eq!(
+            waveform.csv(),
+            "time_s,voltage_v\n-0.500000000,1\n-0.250000000,2\n0.000000000,1.5\n"
+        );
+        assert!(waveform
+            .svg()
+            .contains(r#"points="0.0,380.0 400.0,20.0 800.0,200.0""#));
+    }
+}